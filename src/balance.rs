@@ -1,31 +1,16 @@
-use float_ord::FloatOrd;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::SlowStartConfig;
+use crate::outlier_detection::OutlierDetector;
+use crate::resource_monitor::ResourceMonitor;
 use rand::Rng;
-use reqwest::Error;
-use serde::Deserialize;
 use std::collections::{HashMap, VecDeque};
 use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use twox_hash::XxHash64;
 
-#[derive(Deserialize, Clone, Debug)]
-struct ResourceUsage {
-    cpu_usage_percent: f32,
-    memory_usage_percent: f32,
-}
-
-async fn fetch_resource_usage(
-    client: &reqwest::Client,
-    endpoint: &str,
-) -> Result<ResourceUsage, Error> {
-    client
-        .get(endpoint)
-        .send()
-        .await?
-        .json::<ResourceUsage>()
-        .await
-}
-
 #[derive(Clone, Copy)]
 pub enum BalanceStrategy {
     RoundRobin,
@@ -45,7 +30,157 @@ fn calculate_dynamic_limit(addr: &String, connection_counts: &HashMap<String, us
         100
     }
 }
+
+// Weights are scaled before smooth weighted round robin so that fractional
+// slow-start weights still order correctly as integers.
+const WEIGHT_SCALE: f64 = 1000.0;
+
+pub struct SlowStart {
+    window: Duration,
+    min_weight_fraction: f64,
+    aggression: f64,
+}
+
+impl SlowStart {
+    pub fn new(config: &SlowStartConfig) -> Self {
+        SlowStart {
+            window: Duration::from_secs(config.window_seconds),
+            min_weight_fraction: config.min_weight_percent.unwrap_or(10.0) / 100.0,
+            aggression: config.aggression.unwrap_or(1.0),
+        }
+    }
+
+    // Fraction of the configured weight a target receives `elapsed` after it
+    // became available. An aggression of 1.0 ramps linearly, larger values
+    // ramp up faster early on and smaller values hold the weight down longer.
+    fn weight_fraction(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.window || self.window.is_zero() {
+            return 1.0;
+        }
+        let progress = elapsed.as_secs_f64() / self.window.as_secs_f64();
+        progress
+            .powf(1.0 / self.aggression)
+            .max(self.min_weight_fraction)
+    }
+}
+
+#[derive(Default)]
+struct WeightState {
+    current: HashMap<String, i64>,
+    warming_since: HashMap<String, Instant>,
+    primed: bool,
+}
+
+// Per-route selection state shared by every connection accepted on the route.
+#[derive(Default)]
+pub struct BalancerState {
+    round_robin_cursor: AtomicUsize,
+    weights: Mutex<WeightState>,
+    slow_start: Option<SlowStart>,
+}
+
+impl BalancerState {
+    pub fn new(slow_start: Option<SlowStart>) -> Self {
+        BalancerState {
+            slow_start,
+            ..Self::default()
+        }
+    }
+
+    fn next_round_robin(&self, addrs: &VecDeque<String>) -> Option<String> {
+        let index = self.round_robin_cursor.fetch_add(1, Ordering::Relaxed) % addrs.len();
+        addrs.get(index).cloned()
+    }
+
+    // Nginx-style smooth weighted round robin: every pick raises each candidate's
+    // current weight by its effective weight, takes the highest and lowers it
+    // by the total, so a 5:1:1 split yields a,a,b,a,c,a,a instead of bursts.
+    // The state covers every available target; those in `excluded` take part
+    // in the bookkeeping but are never picked.
+    async fn next_smooth_weighted(
+        &self,
+        addrs: &VecDeque<String>,
+        target_weights: Option<&HashMap<String, usize>>,
+        excluded: &[String],
+    ) -> Option<String> {
+        let now = Instant::now();
+        let mut state = self.weights.lock().await;
+        let WeightState {
+            current,
+            warming_since,
+            primed,
+        } = &mut *state;
+
+        // Targets filtered out as unhealthy start from zero again when they
+        // return, and warm up again if slow start is enabled. Targets present
+        // on the first pick are considered warm already.
+        current.retain(|addr, _| addrs.contains(addr));
+        warming_since.retain(|addr, _| addrs.contains(addr));
+        if let Some(slow_start) = &self.slow_start {
+            if *primed {
+                for addr in addrs {
+                    if !current.contains_key(addr) {
+                        warming_since.entry(addr.clone()).or_insert(now);
+                    }
+                }
+            }
+            warming_since.retain(|_, since| now.duration_since(*since) < slow_start.window);
+        }
+        *primed = true;
+
+        let mut total_weight = 0;
+        let mut selected: Option<(&String, i64)> = None;
+        for addr in addrs {
+            let weight = self.effective_weight(addr, target_weights, warming_since, now);
+            if weight == 0 {
+                continue;
+            }
+            let current_weight = current.entry(addr.clone()).or_insert(0);
+            *current_weight += weight;
+            total_weight += weight;
+            if excluded.contains(addr) {
+                continue;
+            }
+            if selected.is_none_or(|(_, best)| *current_weight > best) {
+                selected = Some((addr, *current_weight));
+            }
+        }
+
+        let (addr, _) = selected?;
+        if let Some(current_weight) = current.get_mut(addr) {
+            *current_weight -= total_weight;
+        }
+        Some(addr.clone())
+    }
+
+    fn effective_weight(
+        &self,
+        addr: &String,
+        target_weights: Option<&HashMap<String, usize>>,
+        warming_since: &HashMap<String, Instant>,
+        now: Instant,
+    ) -> i64 {
+        let configured = target_weights
+            .and_then(|weights| weights.get(addr))
+            .copied()
+            .unwrap_or(1) as f64;
+        let fraction = match (&self.slow_start, warming_since.get(addr)) {
+            (Some(slow_start), Some(since)) => {
+                slow_start.weight_fraction(now.duration_since(*since))
+            }
+            _ => 1.0,
+        };
+        let weight = (configured * fraction * WEIGHT_SCALE).round() as i64;
+        if configured > 0.0 {
+            weight.max(1)
+        } else {
+            0
+        }
+    }
+}
+
 impl BalanceStrategy {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(strategy: &str) -> Self {
         match strategy {
             "roundrobin" => BalanceStrategy::RoundRobin,
@@ -60,51 +195,79 @@ impl BalanceStrategy {
         }
     }
 
-    async fn filter_addresses(
+    pub async fn filter_addresses(
         target_addrs: &Arc<Mutex<VecDeque<String>>>,
         health_statuses: Option<&Arc<Mutex<HashMap<String, bool>>>>,
+        outlier_detector: Option<&Arc<OutlierDetector>>,
+        circuit_breaker: Option<&Arc<CircuitBreaker>>,
+        excluded: &[String],
     ) -> VecDeque<String> {
         let lock = target_addrs.lock().await;
-        if let Some(health_statuses) = health_statuses {
+        let mut filtered = if let Some(health_statuses) = health_statuses {
             let health = health_statuses.lock().await;
             lock.iter()
                 .filter(|addr| *health.get(*addr).unwrap_or(&true))
+                .filter(|addr| !excluded.contains(addr))
                 .cloned()
                 .collect::<VecDeque<_>>()
         } else {
-            lock.clone()
+            lock.iter()
+                .filter(|addr| !excluded.contains(addr))
+                .cloned()
+                .collect::<VecDeque<_>>()
+        };
+
+        if let Some(outlier_detector) = outlier_detector {
+            filtered = outlier_detector.retain_available(filtered).await;
         }
+        if let Some(circuit_breaker) = circuit_breaker {
+            filtered = circuit_breaker.retain_available(filtered).await;
+        }
+        filtered
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn select_target(
         &self,
+        state: Arc<BalancerState>,
         target_addrs: Arc<Mutex<VecDeque<String>>>,
-        connection_counts: Arc<Mutex<HashMap<String, usize>>>,
+        connection_counts: Arc<std::sync::Mutex<HashMap<String, usize>>>,
         request_limits: Arc<Mutex<HashMap<String, usize>>>,
         max_requests_per_target: Option<usize>,
-        resource_endpoints: Option<Arc<Mutex<Vec<String>>>>,
+        resource_monitor: Option<Arc<ResourceMonitor>>,
         target_weights: Option<HashMap<String, usize>>,
         health_statuses: Option<Arc<Mutex<HashMap<String, bool>>>>,
+        outlier_detector: Option<Arc<OutlierDetector>>,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+        excluded: &[String],
         client_ip: Option<String>,
     ) -> Option<String> {
-        let filtered_addrs =
-            BalanceStrategy::filter_addresses(&target_addrs, health_statuses.as_ref()).await;
+        let available_addrs = BalanceStrategy::filter_addresses(
+            &target_addrs,
+            health_statuses.as_ref(),
+            outlier_detector.as_ref(),
+            circuit_breaker.as_ref(),
+            &[],
+        )
+        .await;
+        let filtered_addrs = available_addrs
+            .iter()
+            .filter(|addr| !excluded.contains(addr))
+            .cloned()
+            .collect::<VecDeque<_>>();
         let addrs_len = filtered_addrs.len();
         if addrs_len == 0 {
             return None;
         }
 
         match *self {
-            BalanceStrategy::RoundRobin => {
-                let addr = filtered_addrs.front().cloned();
-                addr
-            }
+            BalanceStrategy::RoundRobin => state.next_round_robin(&filtered_addrs),
             BalanceStrategy::Random => {
                 let mut rng = rand::thread_rng();
                 filtered_addrs.get(rng.gen_range(0..addrs_len)).cloned()
             }
             BalanceStrategy::LeastConnections => {
-                let counts = connection_counts.lock().await;
+                let counts = connection_counts.lock().unwrap();
                 filtered_addrs
                     .iter()
                     .min_by_key(|addr| counts.get(*addr).unwrap_or(&usize::MAX))
@@ -125,51 +288,20 @@ impl BalanceStrategy {
                     .cloned()
             }
             BalanceStrategy::ResourceBased => {
-                if let Some(resource_endpoints) = &resource_endpoints {
-                    let endpoints = resource_endpoints.lock().await;
-                    let mut scores = Vec::new();
-                    let client = reqwest::Client::builder().build().unwrap();
-                    for (index, endpoint) in endpoints.iter().enumerate() {
-                        if let Ok(usage) = fetch_resource_usage(&client, endpoint).await {
-                            let score = usage.cpu_usage_percent + usage.memory_usage_percent;
-                            scores.push((index, FloatOrd(score)));
-                        }
-                    }
-
-                    scores
-                        .iter()
-                        .min_by_key(|(_, score)| score)
-                        .and_then(|(index, _)| filtered_addrs.get(*index).cloned())
-                } else {
-                    None
-                }
+                let least_loaded = match &resource_monitor {
+                    Some(monitor) => monitor.least_loaded(&filtered_addrs).await,
+                    None => None,
+                };
+                // Without fresh usage data, keep traffic flowing in rotation.
+                least_loaded.or_else(|| state.next_round_robin(&filtered_addrs))
             }
             BalanceStrategy::WeightedRoundRobin => {
-                let total_weight: usize = if let Some(ref weights) = target_weights {
-                    weights.values().sum()
-                } else {
-                    filtered_addrs.len()
-                };
-
-                let mut rng = rand::thread_rng();
-                let mut weight_point = rng.gen_range(0..total_weight);
-
-                for addr in filtered_addrs.iter() {
-                    let weight = match &target_weights {
-                        Some(weights) => *weights.get(addr).unwrap_or(&1),
-                        None => 1,
-                    };
-
-                    if weight_point < weight {
-                        return Some(addr.clone());
-                    }
-                    weight_point -= weight;
-                }
-
-                None
+                state
+                    .next_smooth_weighted(&available_addrs, target_weights.as_ref(), excluded)
+                    .await
             }
             BalanceStrategy::DynamicRateLimiting => {
-                let counts = connection_counts.lock().await;
+                let counts = connection_counts.lock().unwrap();
                 filtered_addrs
                     .iter()
                     .filter_map(|addr| {
@@ -190,13 +322,14 @@ impl BalanceStrategy {
                     hasher.write(ip.as_bytes());
                     let ip_hash = hasher.finish();
 
+                    // Hash over every configured target so clients keep their
+                    // target while others come and go, and rehash over the
+                    // available ones when it is unhealthy or excluded.
                     let lock = target_addrs.lock().await;
-                    let addrs_len = lock.len();
-                    if addrs_len == 0 {
-                        None
-                    } else {
-                        lock.get((ip_hash as usize) % addrs_len).cloned()
-                    }
+                    lock.get((ip_hash as usize) % lock.len().max(1))
+                        .filter(|addr| filtered_addrs.contains(*addr))
+                        .or_else(|| filtered_addrs.get((ip_hash as usize) % addrs_len))
+                        .cloned()
                 } else {
                     None
                 }
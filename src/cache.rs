@@ -1,79 +1,585 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use crate::cache_policy::{has_validators, Freshness, MAX_DELTA_SECONDS};
+use crate::compression::Encoding;
+use crate::disk_cache::DiskCache;
+use crate::http::{Headers, ResponseHead};
+use crate::lru::Lru;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_SHARDS: usize = 16;
+pub const DEFAULT_SURROGATE_KEY_HEADER: &str = "Surrogate-Key";
+
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub head: ResponseHead,
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    // Approximate memory use, counting the head as it is sent.
+    fn size(&self) -> usize {
+        self.head.to_bytes().len() + self.body.len()
+    }
+}
+
+pub struct CacheHit {
+    pub response: CachedResponse,
+    pub age: Duration,
+    // Whether an expired entry is still within its stale-while-revalidate or
+    // stale-if-error window.
+    pub stale_while_revalidate: bool,
+    pub stale_if_error: bool,
+}
+
+pub enum CacheLookup {
+    Fresh(CacheHit),
+    // Expired, but either still servable within a stale window or carrying
+    // validators the origin can confirm it by.
+    Stale(CacheHit),
+    Miss,
+}
+
+// When a response was stored and until when it may be served. Wall-clock
+// times, so that entries on disk outlive a restart.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Lifetime {
+    stored_at: SystemTime,
+    expiry: SystemTime,
+    stale_while_revalidate_until: SystemTime,
+    stale_if_error_until: SystemTime,
+}
+
+impl Lifetime {
+    pub fn new(freshness: Freshness, now: SystemTime) -> Self {
+        // Configured lifetimes are capped like those sent by targets, so that
+        // none of them can overflow `SystemTime`.
+        let after = |time: SystemTime, duration: Duration| {
+            let duration = duration.min(Duration::from_secs(MAX_DELTA_SECONDS));
+            time.checked_add(duration).unwrap_or(time)
+        };
+        let expiry = after(now, freshness.ttl);
+        Lifetime {
+            stored_at: now,
+            expiry,
+            stale_while_revalidate_until: after(expiry, freshness.stale_while_revalidate),
+            stale_if_error_until: after(expiry, freshness.stale_if_error),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.expiry
+    }
+
+    // Whether an entry is worth keeping: fresh, within a stale window, or
+    // revalidatable.
+    pub fn is_retained(&self, now: SystemTime, revalidatable: bool) -> bool {
+        !self.is_expired(now)
+            || now <= self.stale_while_revalidate_until
+            || now <= self.stale_if_error_until
+            || revalidatable
+    }
+
+    fn lookup(&self, response: CachedResponse, now: SystemTime) -> CacheLookup {
+        let expired = self.is_expired(now);
+        let hit = CacheHit {
+            response,
+            age: now.duration_since(self.stored_at).unwrap_or_default(),
+            stale_while_revalidate: expired && now <= self.stale_while_revalidate_until,
+            stale_if_error: expired && now <= self.stale_if_error_until,
+        };
+        if expired {
+            CacheLookup::Stale(hit)
+        } else {
+            CacheLookup::Fresh(hit)
+        }
+    }
+}
 
 pub struct CacheEntry {
-    value: Vec<u8>,
-    expiry: Instant,
-    last_accessed: Instant,
+    response: CachedResponse,
+    lifetime: Lifetime,
+    tags: Vec<String>,
 }
 
 impl CacheEntry {
-    pub fn new(value: Vec<u8>, ttl: Duration, now: Instant) -> Self {
-        CacheEntry {
-            value,
-            expiry: now + ttl,
-            last_accessed: now,
+    // Expired entries with validators are kept so they can be revalidated.
+    fn is_retained(&self, now: SystemTime) -> bool {
+        self.lifetime
+            .is_retained(now, has_validators(&self.response.head))
+    }
+}
+
+// What to remove from the cache: everything stored under a key, under keys
+// starting with a prefix, or tagged with a surrogate key by the origin.
+#[derive(Clone, Debug)]
+pub enum Purge {
+    Key(String),
+    Prefix(String),
+    Tag(String),
+}
+
+impl Purge {
+    pub(crate) fn matches(&self, key: &str, tags: &[String]) -> bool {
+        match self {
+            Purge::Key(purged) => key == purged,
+            Purge::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Purge::Tag(tag) => tags.iter().any(|candidate| candidate == tag),
+        }
+    }
+}
+
+// Limits on what the cache holds in memory. Zero means unlimited, but a cache
+// without an entry or byte limit keeps nothing in memory. Each shard holds an
+// equal share of `max_bytes`, which also caps the size of a single entry at
+// `max_bytes / shards`, rounded up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub max_entry_bytes: usize,
+    pub shards: usize,
+}
+
+struct VarySpec {
+    names: Vec<String>,
+    variants: usize,
+}
+
+// The stored variants of each resource in least-recently-used order, along
+// with the header names each resource varies on.
+pub(crate) struct Variants<V> {
+    entries: Lru<V>,
+    // Keyed by primary key, with how many of the resource's variants are
+    // stored.
+    vary: HashMap<String, VarySpec>,
+}
+
+impl<V> Default for Variants<V> {
+    fn default() -> Self {
+        Variants {
+            entries: Lru::default(),
+            vary: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Variants<V> {
+    // The variant of `key` matching the request headers and content coding,
+    // marked as used.
+    pub(crate) fn get_mut(
+        &mut self,
+        key: &str,
+        request_headers: &Headers,
+        encoding: Option<&str>,
+    ) -> Option<(String, &mut V)> {
+        let spec = self.vary.get(key)?;
+        let variant_key = variant_key(key, &spec.names, request_headers, encoding);
+        let value = self.entries.get_mut(&variant_key)?;
+        Some((variant_key, value))
+    }
+
+    // The header names the resource stored under `key` varies on.
+    pub(crate) fn vary_names(&self, key: &str) -> Option<Vec<String>> {
+        self.vary.get(key).map(|spec| spec.names.clone())
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        key: String,
+        variant_key: String,
+        names: Vec<String>,
+        value: V,
+        size: usize,
+    ) -> Option<V> {
+        let replaced = self.entries.insert(variant_key, value, size);
+        let spec = self.vary.entry(key).or_insert(VarySpec {
+            names: Vec::new(),
+            variants: 0,
+        });
+        spec.names = names;
+        if replaced.is_none() {
+            spec.variants += 1;
+        }
+        replaced
+    }
+
+    pub(crate) fn remove(&mut self, variant_key: &str) -> Option<V> {
+        let value = self.entries.remove(variant_key)?;
+        self.forget_variant(variant_key);
+        Some(value)
+    }
+
+    // Removes the variants whose primary key and value match, returning their
+    // variant keys and values.
+    pub(crate) fn remove_matching(
+        &mut self,
+        matches: impl Fn(&str, &V) -> bool,
+    ) -> Vec<(String, V)> {
+        let variant_keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(variant_key, value)| matches(primary_key(variant_key), value))
+            .map(|(variant_key, _)| variant_key.to_string())
+            .collect();
+        variant_keys
+            .into_iter()
+            .filter_map(|variant_key| {
+                let value = self.remove(&variant_key)?;
+                Some((variant_key, value))
+            })
+            .collect()
+    }
+
+    fn forget_variant(&mut self, variant_key: &str) {
+        let key = primary_key(variant_key);
+        if let Some(spec) = self.vary.get_mut(key) {
+            spec.variants = spec.variants.saturating_sub(1);
+            if spec.variants == 0 {
+                self.vary.remove(key);
+            }
         }
     }
 
-    pub fn is_expired(&self, now: Instant) -> bool {
-        now > self.expiry
+    // Values from least to most recently used.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, value)| value)
     }
 
-    pub fn update_last_accessed(&mut self, now: Instant) {
-        self.last_accessed = now;
+    // Drops entries that are no longer worth keeping from the cold end, then
+    // evicts least recently used entries until `size` more bytes fit.
+    // Returns what was removed.
+    pub(crate) fn make_room(
+        &mut self,
+        size: usize,
+        max_entries: usize,
+        max_bytes: usize,
+        retained: impl Fn(&V) -> bool,
+    ) -> Vec<V> {
+        let mut removed = Vec::new();
+        while self
+            .entries
+            .peek_lru()
+            .is_some_and(|(_, value)| !retained(value))
+        {
+            removed.extend(self.pop_lru());
+        }
+        while (max_entries > 0 && self.entries.len() >= max_entries)
+            || (max_bytes > 0 && self.entries.bytes() + size > max_bytes)
+        {
+            match self.pop_lru() {
+                Some(value) => removed.push(value),
+                None => break,
+            }
+        }
+        removed
+    }
+
+    fn pop_lru(&mut self) -> Option<V> {
+        let (variant_key, value) = self.entries.pop_lru()?;
+        self.forget_variant(&variant_key);
+        Some(value)
     }
 }
 
+// A sharded LRU cache bounded by entry count and bytes, optionally backed by
+// a larger tier on disk. Keys are spread over shards by hash so concurrent
+// requests rarely contend for the same lock, and each shard enforces its
+// share of the limits. Responses are written through to the disk tier, and
+// those found there often enough are promoted back into memory.
 pub struct Cache {
-    entries: Arc<RwLock<HashMap<String, CacheEntry>>>,
-    ttl: Duration,
-    capacity: usize,
+    shards: Vec<Mutex<Variants<CacheEntry>>>,
+    hasher: RandomState,
+    max_entries: usize,
+    max_bytes: usize,
+    max_entry_bytes: usize,
+    disk: Option<Arc<DiskCache>>,
+    surrogate_key_header: String,
 }
 
 impl Cache {
-    pub fn new(ttl_seconds: u64, capacity: usize) -> Self {
+    pub fn new(limits: CacheLimits) -> Self {
+        let mut shards = match limits.shards {
+            0 => DEFAULT_SHARDS,
+            shards => shards,
+        };
+        if limits.max_entries > 0 {
+            shards = shards.min(limits.max_entries);
+        }
+        // Rounded up so that a small byte limit is not split into shares of
+        // zero, which would mean unlimited.
+        let max_bytes = limits.max_bytes.div_ceil(shards);
+        let max_entry_bytes = match (limits.max_entry_bytes, max_bytes) {
+            (0, max_bytes) => max_bytes,
+            (max_entry_bytes, 0) => max_entry_bytes,
+            (max_entry_bytes, max_bytes) => max_entry_bytes.min(max_bytes),
+        };
         Cache {
-            entries: Arc::new(RwLock::new(HashMap::new())),
-            ttl: Duration::from_secs(ttl_seconds),
-            capacity,
+            shards: (0..shards)
+                .map(|_| Mutex::new(Variants::default()))
+                .collect(),
+            hasher: RandomState::new(),
+            max_entries: limits.max_entries.div_ceil(shards),
+            max_bytes,
+            max_entry_bytes,
+            disk: None,
+            surrogate_key_header: DEFAULT_SURROGATE_KEY_HEADER.to_string(),
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
-        let now = Instant::now();
-        let mut entries = self.entries.write().await;
-        if let Some(entry) = entries.get_mut(key) {
-            if entry.is_expired(now) {
-                entries.remove(key);
-                return None;
-            }
-            entry.update_last_accessed(now);
-            return Some(entry.value.clone());
+    pub fn with_disk_tier(mut self, disk: Arc<DiskCache>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    // The response header listing the surrogate keys a response is tagged
+    // with, separated by spaces.
+    pub fn with_surrogate_key_header(mut self, name: String) -> Self {
+        self.surrogate_key_header = name;
+        self
+    }
+
+    fn entry(&self, response: CachedResponse, lifetime: Lifetime) -> CacheEntry {
+        let tags = response
+            .head
+            .headers
+            .get_all(&self.surrogate_key_header)
+            .flat_map(str::split_whitespace)
+            .map(str::to_string)
+            .collect();
+        CacheEntry {
+            response,
+            lifetime,
+            tags,
+        }
+    }
+
+    fn is_memory_enabled(&self) -> bool {
+        self.max_entries > 0 || self.max_bytes > 0
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Variants<CacheEntry>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub async fn get(&self, key: &str, request_headers: &Headers) -> CacheLookup {
+        self.lookup(key, request_headers, None).await
+    }
+
+    // A fresh copy of the response in the given content coding, as stored by
+    // `put_encoded`.
+    pub async fn get_encoded(
+        &self,
+        key: &str,
+        request_headers: &Headers,
+        encoding: &str,
+    ) -> Option<CacheHit> {
+        match self.lookup(key, request_headers, Some(encoding)).await {
+            CacheLookup::Fresh(hit) => Some(hit),
+            _ => None,
+        }
+    }
+
+    async fn lookup(
+        &self,
+        key: &str,
+        request_headers: &Headers,
+        encoding: Option<&str>,
+    ) -> CacheLookup {
+        let now = SystemTime::now();
+        if let Some(lookup) = self.get_from_memory(key, request_headers, encoding, now) {
+            return lookup;
+        }
+        let Some(disk) = &self.disk else {
+            return CacheLookup::Miss;
+        };
+        let Some(hit) = disk.get(key, request_headers, encoding).await else {
+            return CacheLookup::Miss;
+        };
+        if hit.promote {
+            let entry = self.entry(hit.response.clone(), hit.lifetime);
+            self.put_in_memory(key.to_string(), hit.vary, hit.variant_key, entry);
         }
-        None
+        hit.lifetime.lookup(hit.response, now)
     }
 
-    pub async fn put(&self, key: String, value: Vec<u8>) {
-        let now = Instant::now();
-        let mut entries = self.entries.write().await;
+    fn get_from_memory(
+        &self,
+        key: &str,
+        request_headers: &Headers,
+        encoding: Option<&str>,
+        now: SystemTime,
+    ) -> Option<CacheLookup> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let (variant_key, entry) = shard.get_mut(key, request_headers, encoding)?;
+        if !entry.is_retained(now) {
+            shard.remove(&variant_key);
+            return None;
+        }
+        Some(entry.lifetime.lookup(entry.response.clone(), now))
+    }
 
-        entries.retain(|_, v| !v.is_expired(now));
+    // Stores a response under `key`, keeping separate variants for the request
+    // header values named in the response's `Vary` header. A response larger
+    // than the per-entry limit is kept only on disk, and drops the variant it
+    // would have replaced from memory.
+    pub async fn put(
+        &self,
+        key: String,
+        request_headers: &Headers,
+        response: CachedResponse,
+        freshness: Freshness,
+    ) {
+        let vary = vary_header_names(&response.head.headers);
+        // Copies re-encoded from the response being replaced are out of date.
+        let encoded: Vec<String> = Encoding::ALL
+            .iter()
+            .map(|encoding| variant_key(&key, &vary, request_headers, Some(encoding.as_str())))
+            .collect();
+        self.remove(&key, &encoded).await;
+        let variant_key = variant_key(&key, &vary, request_headers, None);
+        let entry = self.entry(response, Lifetime::new(freshness, SystemTime::now()));
+        self.store(key, vary, variant_key, entry).await;
+    }
 
-        if entries.len() >= self.capacity && !entries.contains_key(&key) {
-            let lru_key = entries
-                .iter()
-                .filter(|(_, entry)| !entry.is_expired(now))
-                .min_by_key(|(_, entry)| entry.last_accessed)
-                .map(|(k, _)| k.clone());
+    // Stores a response re-encoded from the one stored under `key` for the
+    // same request headers, alongside it and with the same lifetime. Nothing
+    // is stored when that response is no longer cached.
+    pub async fn put_encoded(
+        &self,
+        key: String,
+        request_headers: &Headers,
+        encoding: &str,
+        response: CachedResponse,
+    ) {
+        let stored = {
+            let mut shard = self.shard(&key).lock().unwrap();
+            let vary = shard.vary_names(&key);
+            let lifetime = shard
+                .get_mut(&key, request_headers, None)
+                .map(|(_, entry)| entry.lifetime);
+            vary.zip(lifetime)
+        };
+        let stored = match (stored, &self.disk) {
+            (Some(stored), _) => Some(stored),
+            (None, Some(disk)) => disk.identity(&key, request_headers),
+            (None, None) => None,
+        };
+        let Some((vary, lifetime)) = stored else {
+            return;
+        };
+        let variant_key = variant_key(&key, &vary, request_headers, Some(encoding));
+        let entry = self.entry(response, lifetime);
+        self.store(key, vary, variant_key, entry).await;
+    }
 
-            if let Some(k) = lru_key {
-                entries.remove(&k);
+    async fn store(&self, key: String, vary: Vec<String>, variant_key: String, entry: CacheEntry) {
+        if let Some(disk) = &self.disk {
+            disk.put(
+                &key,
+                &vary,
+                &variant_key,
+                &entry.response,
+                entry.lifetime,
+                &entry.tags,
+            )
+            .await;
+        }
+        self.put_in_memory(key, vary, variant_key, entry);
+    }
+
+    async fn remove(&self, key: &str, variant_keys: &[String]) {
+        {
+            let mut shard = self.shard(key).lock().unwrap();
+            for variant_key in variant_keys {
+                shard.remove(variant_key);
             }
         }
+        if let Some(disk) = &self.disk {
+            disk.remove(variant_keys).await;
+        }
+    }
+
+    // Removes matching entries from memory and disk, returning how many
+    // responses were removed.
+    pub async fn purge(&self, purge: &Purge) -> usize {
+        let mut purged: HashSet<String> = HashSet::new();
+        for shard in &self.shards {
+            let removed = shard
+                .lock()
+                .unwrap()
+                .remove_matching(|key, entry| purge.matches(key, &entry.tags));
+            purged.extend(removed.into_iter().map(|(variant_key, _)| variant_key));
+        }
+        if let Some(disk) = &self.disk {
+            purged.extend(disk.purge(purge).await);
+        }
+        purged.len()
+    }
+
+    fn put_in_memory(
+        &self,
+        key: String,
+        vary: Vec<String>,
+        variant_key: String,
+        entry: CacheEntry,
+    ) {
+        if !self.is_memory_enabled() {
+            return;
+        }
+        let now = SystemTime::now();
+        let size = variant_key.len() + entry.response.size();
+        let mut shard = self.shard(&key).lock().unwrap();
 
-        entries.insert(key, CacheEntry::new(value, self.ttl, now));
+        shard.remove(&variant_key);
+        if self.max_entry_bytes > 0 && size > self.max_entry_bytes {
+            return;
+        }
+        shard.make_room(size, self.max_entries, self.max_bytes, |entry| {
+            entry.is_retained(now)
+        });
+        shard.insert(key, variant_key, vary, entry, size);
+    }
+}
+
+fn primary_key(variant_key: &str) -> &str {
+    variant_key.split('\n').next().unwrap_or_default()
+}
+
+fn vary_header_names(headers: &Headers) -> Vec<String> {
+    let mut names: Vec<String> = headers
+        .get_all("Vary")
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Identifies one stored variant of a resource: the values of the request
+// headers it varies on and, for re-encoded copies, the content coding.
+fn variant_key(
+    key: &str,
+    vary: &[String],
+    request_headers: &Headers,
+    encoding: Option<&str>,
+) -> String {
+    let mut variant = key.to_string();
+    for name in vary {
+        variant.push('\n');
+        variant.push_str(name);
+        variant.push('=');
+        variant.push_str(request_headers.get(name).unwrap_or_default());
+    }
+    if let Some(encoding) = encoding {
+        variant.push_str("\ncontent-encoding=");
+        variant.push_str(encoding);
     }
+    variant
 }
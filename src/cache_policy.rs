@@ -0,0 +1,512 @@
+use crate::cache_rules::{CacheRule, CacheRules};
+use crate::http::{Headers, RequestHead, ResponseHead};
+use std::time::{Duration, SystemTime};
+
+const CACHEABLE_METHODS: [&str; 2] = ["GET", "HEAD"];
+// Statuses that are cacheable by default (RFC 9110, section 15.1).
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+// Delta-seconds larger than this are taken as this (RFC 9111, section 1.2.2).
+pub const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+#[derive(Debug, Default)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &Headers) -> Self {
+        let mut cache_control = CacheControl::default();
+        let directives = headers
+            .get_all("Cache-Control")
+            .flat_map(|value| value.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = value.and_then(parse_delta_seconds);
+            match name.to_lowercase().as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "public" => cache_control.public = true,
+                "max-age" => cache_control.max_age = seconds,
+                "s-maxage" => cache_control.s_maxage = seconds,
+                "must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+                "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds,
+                "stale-if-error" => cache_control.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+        cache_control
+    }
+}
+
+fn parse_delta_seconds(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seconds = value.parse::<u64>().unwrap_or(MAX_DELTA_SECONDS);
+    Some(seconds.min(MAX_DELTA_SECONDS))
+}
+
+// How long a response stays fresh, and for how long after that it may still
+// be served while it is refreshed in the background or when the origin fails.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Freshness {
+    pub ttl: Duration,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+}
+
+// Decides what the shared cache may store and serve, following the route's
+// cache rules and HTTP caching rules. The matching rule's TTL, or else the
+// route's, applies when the origin gives no freshness information, or to
+// every response when `ttl_override` is set. The route's stale windows
+// likewise apply when the response has no such directives.
+pub struct CachePolicy {
+    rules: CacheRules,
+    default_ttl: Duration,
+    ttl_override: bool,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+}
+
+impl CachePolicy {
+    pub fn new(
+        rules: CacheRules,
+        default_ttl_seconds: u64,
+        ttl_override: bool,
+        stale_while_revalidate_seconds: u64,
+        stale_if_error_seconds: u64,
+    ) -> Self {
+        CachePolicy {
+            rules,
+            default_ttl: Duration::from_secs(default_ttl_seconds),
+            ttl_override,
+            stale_while_revalidate: Duration::from_secs(stale_while_revalidate_seconds),
+            stale_if_error: Duration::from_secs(stale_if_error_seconds),
+        }
+    }
+
+    // Whether a cache rule covers the request and none of its bypass
+    // conditions apply.
+    pub fn is_cacheable(&self, request: &RequestHead) -> bool {
+        self.rules
+            .find(request)
+            .is_some_and(|rule| !rule.bypasses(request))
+    }
+
+    // The key a request is cached under, or None when its method or headers
+    // rule out caching entirely. The matching rule decides which query
+    // parameters are part of it.
+    pub fn cache_key(&self, request: &RequestHead) -> Option<String> {
+        if !CACHEABLE_METHODS
+            .iter()
+            .any(|method| request.method.eq_ignore_ascii_case(method))
+        {
+            return None;
+        }
+        if CacheControl::parse(&request.headers).no_store {
+            return None;
+        }
+        let host = request.headers.get("Host").unwrap_or_default();
+        let (path, query) = split_path(&request.path);
+        let mut key = format!(
+            "{} {}{}",
+            request.method.to_uppercase(),
+            host.to_lowercase(),
+            path
+        );
+        let query = normalize_query(query, self.rules.find(request));
+        if !query.is_empty() {
+            key.push('?');
+            key.push_str(&query);
+        }
+        Some(key)
+    }
+
+    // Whether a stored response may answer this request without going to the
+    // origin.
+    pub fn allows_cached_response(&self, request: &RequestHead) -> bool {
+        let cache_control = CacheControl::parse(&request.headers);
+        let pragma_no_cache = request
+            .headers
+            .get("Pragma")
+            .is_some_and(|pragma| pragma.eq_ignore_ascii_case("no-cache"));
+        !cache_control.no_cache && cache_control.max_age != Some(0) && !pragma_no_cache
+    }
+
+    // How long a response may be stored and served for, or None when it must
+    // not be stored.
+    pub fn freshness(&self, request: &RequestHead, response: &ResponseHead) -> Option<Freshness> {
+        if !CACHEABLE_STATUSES.contains(&response.status) {
+            return None;
+        }
+        let cache_control = CacheControl::parse(&response.headers);
+        if cache_control.no_store || cache_control.private {
+            return None;
+        }
+        if response.headers.get("Set-Cookie").is_some() {
+            return None;
+        }
+        let varies_on_everything = response
+            .headers
+            .get_all("Vary")
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim() == "*");
+        if varies_on_everything {
+            return None;
+        }
+        if request.headers.get("Authorization").is_some()
+            && !cache_control.public
+            && cache_control.s_maxage.is_none()
+        {
+            return None;
+        }
+
+        let rule = self.rules.find(request);
+        let default_ttl = rule.and_then(|rule| rule.ttl).unwrap_or(self.default_ttl);
+        let ttl_override = rule
+            .and_then(|rule| rule.ttl_override)
+            .unwrap_or(self.ttl_override);
+        // A no-cache response is stored already stale, so that every use of it
+        // is revalidated with the target first.
+        let ttl = if cache_control.no_cache {
+            if !has_validators(response) {
+                return None;
+            }
+            Duration::ZERO
+        } else if ttl_override {
+            default_ttl
+        } else if let Some(seconds) = cache_control.s_maxage.or(cache_control.max_age) {
+            Duration::from_secs(seconds)
+        } else if let Some(ttl) = expires_ttl(&response.headers) {
+            ttl
+        } else {
+            default_ttl
+        };
+        if ttl.is_zero() && !cache_control.no_cache {
+            return None;
+        }
+
+        let stale_window = |directive: Option<u64>, default: Duration| {
+            if cache_control.must_revalidate || cache_control.no_cache {
+                Duration::ZERO
+            } else {
+                directive.map(Duration::from_secs).unwrap_or(default)
+            }
+        };
+        Some(Freshness {
+            ttl,
+            stale_while_revalidate: stale_window(
+                cache_control.stale_while_revalidate,
+                self.stale_while_revalidate,
+            ),
+            stale_if_error: stale_window(cache_control.stale_if_error, self.stale_if_error),
+        })
+    }
+}
+
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path, ""),
+    }
+}
+
+// Sorts query parameters so that equivalent URLs share a cache entry, leaving
+// out those the rule excludes from the key.
+fn normalize_query(query: &str, rule: Option<&CacheRule>) -> String {
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let name = param.split_once('=').map_or(*param, |(name, _)| name);
+            rule.is_none_or(|rule| rule.keeps_param(name))
+        })
+        .collect();
+    params.sort_unstable();
+    params.join("&")
+}
+
+// Freshness from `Expires`, measured against the response's `Date` header
+// when present.
+fn expires_ttl(headers: &Headers) -> Option<Duration> {
+    let expires = headers.get("Expires")?;
+    // An invalid date, such as "0", means the response is already stale.
+    let Ok(expires) = httpdate::parse_http_date(expires) else {
+        return Some(Duration::ZERO);
+    };
+    let date = headers
+        .get("Date")
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or(Duration::ZERO))
+}
+
+pub fn has_validators(response: &ResponseHead) -> bool {
+    response.headers.get("ETag").is_some() || response.headers.get("Last-Modified").is_some()
+}
+
+// Turns a request into a conditional one for revalidating `stored`.
+pub fn add_validators(request: &mut RequestHead, stored: &ResponseHead) {
+    request.headers.remove("If-None-Match");
+    request.headers.remove("If-Modified-Since");
+    if let Some(etag) = stored.headers.get("ETag") {
+        request.headers.set("If-None-Match", etag);
+    }
+    if let Some(last_modified) = stored.headers.get("Last-Modified") {
+        request.headers.set("If-Modified-Since", last_modified);
+    }
+}
+
+// Whether the client's conditional headers match `response`, so that it can
+// be answered with 304 Not Modified.
+pub fn not_modified(request: &RequestHead, response: &ResponseHead) -> bool {
+    if let Some(if_none_match) = request.headers.get("If-None-Match") {
+        let Some(etag) = response.headers.get("ETag") else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || weak_eq(candidate, etag));
+    }
+    let if_modified_since = request
+        .headers
+        .get("If-Modified-Since")
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    let last_modified = response
+        .headers
+        .get("Last-Modified")
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// Applies the headers of a 304 response to the stored response it validated.
+pub fn merge_not_modified(stored: &ResponseHead, not_modified: &ResponseHead) -> ResponseHead {
+    let mut merged = stored.clone();
+    let mut updated: Vec<&str> = Vec::new();
+    for (name, _) in not_modified.headers.iter() {
+        let skip = ["Content-Length", "Transfer-Encoding", "Connection"]
+            .iter()
+            .any(|excluded| name.eq_ignore_ascii_case(excluded));
+        if skip || updated.iter().any(|seen| seen.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        updated.push(name);
+        merged.headers.remove(name);
+        for value in not_modified.headers.get_all(name) {
+            merged.headers.append(name, value);
+        }
+    }
+    merged
+}
+
+// The 304 answer to a client whose conditional request matched `stored`.
+pub fn not_modified_response(stored: &ResponseHead) -> ResponseHead {
+    let mut headers = Headers::default();
+    let kept = [
+        "Cache-Control",
+        "Content-Location",
+        "Date",
+        "ETag",
+        "Expires",
+        "Last-Modified",
+        "Vary",
+    ];
+    for (name, value) in stored.headers.iter() {
+        if kept.iter().any(|kept| name.eq_ignore_ascii_case(kept)) {
+            headers.append(name, value);
+        }
+    }
+    ResponseHead {
+        version: stored.version.clone(),
+        status: 304,
+        reason: "Not Modified".to_string(),
+        headers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{not_modified, CachePolicy, MAX_DELTA_SECONDS};
+    use crate::cache_rules::CacheRules;
+    use crate::http::{Headers, RequestHead, ResponseHead};
+    use std::time::Duration;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in pairs {
+            headers.append(name, value);
+        }
+        headers
+    }
+
+    fn request(method: &str, path: &str, pairs: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: method.to_string(),
+            path: path.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers(pairs),
+        }
+    }
+
+    fn response(pairs: &[(&str, &str)]) -> ResponseHead {
+        ResponseHead {
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            reason: "OK".to_string(),
+            headers: headers(pairs),
+        }
+    }
+
+    fn policy() -> CachePolicy {
+        CachePolicy::new(CacheRules::new(Vec::new()), 60, false, 0, 0)
+    }
+
+    fn ttl(request: &RequestHead, pairs: &[(&str, &str)]) -> Option<Duration> {
+        policy()
+            .freshness(request, &response(pairs))
+            .map(|freshness| freshness.ttl)
+    }
+
+    #[test]
+    fn does_not_store_private_or_personal_responses() {
+        let get = request("GET", "/", &[]);
+        assert_eq!(ttl(&get, &[("Cache-Control", "no-store")]), None);
+        assert_eq!(ttl(&get, &[("Cache-Control", "private, max-age=60")]), None);
+        assert_eq!(ttl(&get, &[("Set-Cookie", "session=1")]), None);
+        assert_eq!(ttl(&get, &[]), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn does_not_store_responses_varying_on_everything() {
+        let get = request("GET", "/", &[]);
+        assert_eq!(ttl(&get, &[("Vary", "Accept-Encoding, *")]), None);
+        assert!(ttl(&get, &[("Vary", "Accept-Encoding")]).is_some());
+    }
+
+    #[test]
+    fn prefers_s_maxage_over_max_age() {
+        let get = request("GET", "/", &[]);
+        let cache_control = [("Cache-Control", "max-age=10, s-maxage=30")];
+        assert_eq!(ttl(&get, &cache_control), Some(Duration::from_secs(30)));
+        let cache_control = [("Cache-Control", "max-age=10")];
+        assert_eq!(ttl(&get, &cache_control), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn stores_no_cache_responses_as_stale() {
+        let get = request("GET", "/", &[]);
+        let with_etag = [
+            ("Cache-Control", "no-cache, max-age=60"),
+            ("ETag", "\"v1\""),
+        ];
+        assert_eq!(ttl(&get, &with_etag), Some(Duration::ZERO));
+        assert_eq!(
+            ttl(&get, &[("Cache-Control", "no-cache, max-age=60")]),
+            None
+        );
+    }
+
+    #[test]
+    fn caps_delta_seconds() {
+        let get = request("GET", "/", &[]);
+        let huge = [("Cache-Control", "max-age=99999999999999999999999")];
+        assert_eq!(
+            ttl(&get, &huge),
+            Some(Duration::from_secs(MAX_DELTA_SECONDS))
+        );
+        assert_eq!(
+            ttl(&get, &[("Cache-Control", "max-age=-1")]),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn measures_expires_from_date() {
+        let get = request("GET", "/", &[]);
+        let expires = [
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+        ];
+        assert_eq!(ttl(&get, &expires), Some(Duration::from_secs(3600)));
+        assert_eq!(ttl(&get, &[("Expires", "0")]), None);
+    }
+
+    #[test]
+    fn stores_authorized_responses_only_when_shared() {
+        let authorized = request("GET", "/", &[("Authorization", "Bearer token")]);
+        assert_eq!(ttl(&authorized, &[("Cache-Control", "max-age=60")]), None);
+        assert!(ttl(&authorized, &[("Cache-Control", "public, max-age=60")]).is_some());
+        assert!(ttl(&authorized, &[("Cache-Control", "s-maxage=60")]).is_some());
+    }
+
+    #[test]
+    fn keys_requests_by_method_host_path_and_sorted_query() {
+        let policy = policy();
+        let get = request("get", "/items?b=2&a=1", &[("Host", "Example.COM")]);
+        assert_eq!(
+            policy.cache_key(&get).as_deref(),
+            Some("GET example.com/items?a=1&b=2")
+        );
+        assert_eq!(policy.cache_key(&request("POST", "/items", &[])), None);
+        let no_store = request("GET", "/items", &[("Cache-Control", "no-store")]);
+        assert_eq!(policy.cache_key(&no_store), None);
+    }
+
+    #[test]
+    fn matches_etags_weakly() {
+        let stored = response(&[("ETag", "W/\"v1\"")]);
+        let matching = ["\"v1\"", "W/\"v1\"", "\"v0\", \"v1\"", "*"];
+        for if_none_match in matching {
+            let conditional = request("GET", "/", &[("If-None-Match", if_none_match)]);
+            assert!(not_modified(&conditional, &stored), "{}", if_none_match);
+        }
+        let other = request("GET", "/", &[("If-None-Match", "\"v2\"")]);
+        assert!(!not_modified(&other, &stored));
+        let unconditional = request("GET", "/", &[]);
+        assert!(!not_modified(&unconditional, &stored));
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        let stored = response(&[("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        let since = |date| request("GET", "/", &[("If-Modified-Since", date)]);
+        assert!(not_modified(
+            &since("Sun, 06 Nov 1994 08:49:37 GMT"),
+            &stored
+        ));
+        assert!(!not_modified(
+            &since("Sat, 05 Nov 1994 08:49:37 GMT"),
+            &stored
+        ));
+        // An ETag condition takes precedence over the date.
+        let both = request(
+            "GET",
+            "/",
+            &[
+                ("If-None-Match", "\"v2\""),
+                ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ],
+        );
+        assert!(!not_modified(&both, &stored));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::cache::Purge;
+use crate::cache_policy::CachePolicy;
+use crate::http::RequestHead;
+use ipnet::IpNet;
+use log::warn;
+use std::net::IpAddr;
+
+// Accepts HTTP PURGE requests from allow-listed networks and turns them into
+// cache purges.
+pub struct PurgeEndpoint {
+    allowed_networks: Vec<IpNet>,
+    surrogate_key_header: String,
+}
+
+impl PurgeEndpoint {
+    // Networks are given in CIDR notation or as single addresses.
+    pub fn new(allowed_networks: &[String], surrogate_key_header: &str) -> Self {
+        let allowed_networks = allowed_networks
+            .iter()
+            .filter_map(|network| {
+                let parsed = network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from));
+                match parsed {
+                    Ok(network) => Some(network),
+                    Err(e) => {
+                        warn!("Ignoring invalid cache purge network {}: {}", network, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        PurgeEndpoint {
+            allowed_networks,
+            surrogate_key_header: surrogate_key_header.to_string(),
+        }
+    }
+
+    pub fn is_purge(request: &RequestHead) -> bool {
+        request.method.eq_ignore_ascii_case("PURGE")
+    }
+
+    pub fn allows(&self, client_ip: &str) -> bool {
+        client_ip.parse::<IpAddr>().is_ok_and(|ip| {
+            self.allowed_networks
+                .iter()
+                .any(|network| network.contains(&ip))
+        })
+    }
+
+    // `PURGE /path` removes the cached GET and HEAD responses for that URL on
+    // the request's host, and `PURGE /path*` everything under it. With a
+    // surrogate key header, everything tagged with any of its keys is removed
+    // instead.
+    pub fn purges(&self, request: &RequestHead, policy: &CachePolicy) -> Vec<Purge> {
+        let tags: Vec<Purge> = request
+            .headers
+            .get_all(&self.surrogate_key_header)
+            .flat_map(str::split_whitespace)
+            .map(|tag| Purge::Tag(tag.to_string()))
+            .collect();
+        if !tags.is_empty() {
+            return tags;
+        }
+
+        let (path, prefix) = match request.path.strip_suffix('*') {
+            Some(path) => (path, true),
+            None => (request.path.as_str(), false),
+        };
+        ["GET", "HEAD"]
+            .into_iter()
+            .filter_map(|method| {
+                let mut cached = request.clone();
+                cached.method = method.to_string();
+                cached.path = path.to_string();
+                cached.headers.remove("Cache-Control");
+                policy.cache_key(&cached)
+            })
+            .map(|key| match prefix {
+                true => Purge::Prefix(key),
+                false => Purge::Key(key),
+            })
+            .collect()
+    }
+}
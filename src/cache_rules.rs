@@ -0,0 +1,132 @@
+use crate::cache_policy::split_path;
+use crate::config::CacheRuleConfig;
+use crate::http::RequestHead;
+use log::warn;
+use regex::Regex;
+use std::time::Duration;
+
+const DEFAULT_METHODS: [&str; 2] = ["GET", "HEAD"];
+
+// Which requests a route caches, and how. A rule matches requests by path,
+// given as a glob or a regular expression, and by method.
+pub struct CacheRule {
+    path: Regex,
+    methods: Vec<String>,
+    query_include: Option<Vec<String>>,
+    query_exclude: Vec<String>,
+    pub ttl: Option<Duration>,
+    pub ttl_override: Option<bool>,
+    bypass_cookies: Vec<String>,
+    bypass_headers: Vec<String>,
+}
+
+impl CacheRule {
+    pub fn new(config: &CacheRuleConfig) -> Option<Self> {
+        let pattern = match (&config.path, &config.path_regex) {
+            (_, Some(pattern)) => pattern.clone(),
+            (Some(glob), None) => glob_to_regex(glob),
+            (None, None) => {
+                warn!("Ignoring cache rule without a path or path_regex");
+                return None;
+            }
+        };
+        let path = match Regex::new(&pattern) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("Ignoring cache rule with invalid path {}: {}", pattern, e);
+                return None;
+            }
+        };
+        Some(CacheRule {
+            path,
+            methods: config
+                .methods
+                .clone()
+                .unwrap_or_else(|| DEFAULT_METHODS.map(str::to_string).to_vec()),
+            query_include: config.query_include.clone(),
+            query_exclude: config.query_exclude.clone().unwrap_or_default(),
+            ttl: config.ttl_seconds.map(Duration::from_secs),
+            ttl_override: config.ttl_override,
+            bypass_cookies: config.bypass_cookies.clone().unwrap_or_default(),
+            bypass_headers: config.bypass_headers.clone().unwrap_or_default(),
+        })
+    }
+
+    // A rule matching exactly one path, as `cache_enabled_endpoints` lists.
+    pub fn exact_path(path: &str) -> Self {
+        CacheRule {
+            path: Regex::new(&format!("^{}$", regex::escape(path))).expect("escaped path"),
+            methods: DEFAULT_METHODS.map(str::to_string).to_vec(),
+            query_include: None,
+            query_exclude: Vec::new(),
+            ttl: None,
+            ttl_override: None,
+            bypass_cookies: Vec::new(),
+            bypass_headers: Vec::new(),
+        }
+    }
+
+    fn matches(&self, request: &RequestHead) -> bool {
+        let (path, _) = split_path(&request.path);
+        self.methods
+            .iter()
+            .any(|method| request.method.eq_ignore_ascii_case(method))
+            && self.path.is_match(path)
+    }
+
+    // Whether the request carries a cookie or header that rules out caching.
+    pub fn bypasses(&self, request: &RequestHead) -> bool {
+        self.bypass_cookies
+            .iter()
+            .any(|name| request.cookie(name).is_some())
+            || self
+                .bypass_headers
+                .iter()
+                .any(|name| request.headers.get(name).is_some())
+    }
+
+    // Whether a query parameter is part of the cache key.
+    pub fn keeps_param(&self, name: &str) -> bool {
+        let included = self
+            .query_include
+            .as_ref()
+            .is_none_or(|include| include.iter().any(|kept| kept == name));
+        included && !self.query_exclude.iter().any(|dropped| dropped == name)
+    }
+}
+
+// The first matching rule applies.
+#[derive(Default)]
+pub struct CacheRules {
+    rules: Vec<CacheRule>,
+}
+
+impl CacheRules {
+    pub fn new(rules: Vec<CacheRule>) -> Self {
+        CacheRules { rules }
+    }
+
+    pub fn find(&self, request: &RequestHead) -> Option<&CacheRule> {
+        self.rules.iter().find(|rule| rule.matches(request))
+    }
+}
+
+// `*` matches within a path segment, `**` across segments and `?` a single
+// character.
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
@@ -0,0 +1,284 @@
+use crate::config::CircuitBreakerConfig;
+use crate::metrics::Metrics;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_WINDOW_SECONDS: u64 = 30;
+const DEFAULT_MINIMUM_REQUESTS: usize = 10;
+const DEFAULT_OPEN_SECONDS: u64 = 30;
+const DEFAULT_HALF_OPEN_PROBES: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+
+    fn gauge_value(&self) -> f64 {
+        match self {
+            CircuitState::Closed => 0.0,
+            CircuitState::Open => 1.0,
+            CircuitState::HalfOpen => 2.0,
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    opened_at: Instant,
+    outcomes: VecDeque<(Instant, bool)>,
+    consecutive_failures: u32,
+    probes_in_flight: u32,
+    probe_successes: u32,
+    // Bumped on every transition, so that permits taken before it no longer
+    // count against the probe slots.
+    generation: u64,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            opened_at: Instant::now(),
+            outcomes: VecDeque::new(),
+            consecutive_failures: 0,
+            probes_in_flight: 0,
+            probe_successes: 0,
+            generation: 0,
+        }
+    }
+}
+
+// Trips a target's circuit open when its failure ratio or consecutive
+// failures within the rolling window exceed the configured limits. After the
+// open duration a limited number of probe requests are let through, and the
+// circuit closes again once they all succeed.
+pub struct CircuitBreaker {
+    failure_ratio: Option<f64>,
+    consecutive_failures: Option<u32>,
+    minimum_requests: usize,
+    window: Duration,
+    open_duration: Duration,
+    half_open_probes: u32,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    metrics: Arc<Metrics>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig, metrics: Arc<Metrics>) -> Self {
+        let consecutive_failures = config.consecutive_failures;
+        // With neither trigger configured, trip at a 50% failure ratio.
+        let failure_ratio = match (config.failure_ratio, consecutive_failures) {
+            (None, None) => Some(0.5),
+            (ratio, _) => ratio,
+        };
+        CircuitBreaker {
+            failure_ratio,
+            consecutive_failures,
+            minimum_requests: config.minimum_requests.unwrap_or(DEFAULT_MINIMUM_REQUESTS),
+            window: Duration::from_secs(config.window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS)),
+            open_duration: Duration::from_secs(config.open_seconds.unwrap_or(DEFAULT_OPEN_SECONDS)),
+            half_open_probes: config
+                .half_open_probes
+                .unwrap_or(DEFAULT_HALF_OPEN_PROBES)
+                .max(1),
+            circuits: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    pub async fn retain_available(&self, addrs: VecDeque<String>) -> VecDeque<String> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        addrs
+            .into_iter()
+            .filter(|addr| match circuits.get_mut(addr) {
+                Some(circuit) => {
+                    self.refresh(addr, circuit, now);
+                    match circuit.state {
+                        CircuitState::Closed => true,
+                        CircuitState::Open => false,
+                        CircuitState::HalfOpen => circuit.probes_in_flight < self.half_open_probes,
+                    }
+                }
+                None => true,
+            })
+            .collect()
+    }
+
+    // Claims permission to send a request to `addr`. Half-open circuits only
+    // admit as many concurrent requests as there are probe slots, and the
+    // returned permit holds its slot until dropped.
+    pub async fn try_acquire(self: &Arc<Self>, addr: &str) -> Option<CircuitPermit> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(addr) else {
+            return Some(CircuitPermit { _probe: None });
+        };
+        self.refresh(addr, circuit, now);
+        let permit = match circuit.state {
+            CircuitState::Closed => Some(CircuitPermit { _probe: None }),
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if circuit.probes_in_flight < self.half_open_probes => {
+                circuit.probes_in_flight += 1;
+                Some(CircuitPermit {
+                    _probe: Some(ProbeSlot {
+                        breaker: Arc::clone(self),
+                        addr: addr.to_string(),
+                        generation: circuit.generation,
+                    }),
+                })
+            }
+            CircuitState::HalfOpen => None,
+        };
+        if permit.is_none() {
+            self.metrics.increment(
+                "road47_circuit_breaker_rejections_total",
+                &[("target", addr)],
+            );
+        }
+        permit
+    }
+
+    pub async fn record_success(&self, addr: &str) {
+        self.record(addr, true).await;
+    }
+
+    pub async fn record_failure(&self, addr: &str) {
+        self.record(addr, false).await;
+    }
+
+    async fn record(&self, addr: &str, success: bool) {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(addr.to_string())
+            .or_insert_with(Circuit::new);
+        match circuit.state {
+            CircuitState::Closed => {
+                circuit.outcomes.push_back((now, success));
+                while circuit
+                    .outcomes
+                    .front()
+                    .is_some_and(|(at, _)| now.duration_since(*at) > self.window)
+                {
+                    circuit.outcomes.pop_front();
+                }
+                circuit.consecutive_failures = if success {
+                    0
+                } else {
+                    circuit.consecutive_failures + 1
+                };
+                if self.should_trip(circuit) {
+                    self.transition(addr, circuit, CircuitState::Open, now);
+                }
+            }
+            CircuitState::HalfOpen => {
+                if success {
+                    circuit.probe_successes += 1;
+                    if circuit.probe_successes >= self.half_open_probes {
+                        self.transition(addr, circuit, CircuitState::Closed, now);
+                    }
+                } else {
+                    self.transition(addr, circuit, CircuitState::Open, now);
+                }
+            }
+            // Late results from requests sent before the circuit opened.
+            CircuitState::Open => {}
+        }
+    }
+
+    fn should_trip(&self, circuit: &Circuit) -> bool {
+        if self
+            .consecutive_failures
+            .is_some_and(|limit| circuit.consecutive_failures >= limit)
+        {
+            return true;
+        }
+        let Some(ratio) = self.failure_ratio else {
+            return false;
+        };
+        let total = circuit.outcomes.len();
+        if total < self.minimum_requests {
+            return false;
+        }
+        let failures = circuit.outcomes.iter().filter(|(_, ok)| !ok).count();
+        failures as f64 / total as f64 >= ratio
+    }
+
+    fn refresh(&self, addr: &str, circuit: &mut Circuit, now: Instant) {
+        if circuit.state == CircuitState::Open
+            && now.duration_since(circuit.opened_at) >= self.open_duration
+        {
+            self.transition(addr, circuit, CircuitState::HalfOpen, now);
+        }
+    }
+
+    fn transition(&self, addr: &str, circuit: &mut Circuit, state: CircuitState, now: Instant) {
+        match state {
+            CircuitState::Open => {
+                warn!("Circuit for {} opened", addr);
+                circuit.opened_at = now;
+            }
+            CircuitState::HalfOpen => info!("Circuit for {} half-open, probing", addr),
+            CircuitState::Closed => {
+                info!("Circuit for {} closed", addr);
+                circuit.outcomes.clear();
+                circuit.consecutive_failures = 0;
+            }
+        }
+        circuit.state = state;
+        circuit.probes_in_flight = 0;
+        circuit.probe_successes = 0;
+        circuit.generation += 1;
+
+        self.metrics.increment(
+            "road47_circuit_breaker_transitions_total",
+            &[("target", addr), ("state", state.as_str())],
+        );
+        self.metrics.set_gauge(
+            "road47_circuit_breaker_state",
+            &[("target", addr)],
+            state.gauge_value(),
+        );
+    }
+}
+
+// Permission to send one request through a circuit. Permits taken while the
+// circuit is half-open hold a probe slot until they are dropped, whether or
+// not an outcome was recorded for the request. The default permit is for
+// targets without a circuit breaker.
+#[derive(Default)]
+pub struct CircuitPermit {
+    _probe: Option<ProbeSlot>,
+}
+
+struct ProbeSlot {
+    breaker: Arc<CircuitBreaker>,
+    addr: String,
+    generation: u64,
+}
+
+impl Drop for ProbeSlot {
+    fn drop(&mut self) {
+        let mut circuits = self.breaker.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(&self.addr) {
+            if circuit.generation == self.generation {
+                circuit.probes_in_flight = circuit.probes_in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
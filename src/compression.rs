@@ -0,0 +1,239 @@
+use crate::config::CompressionConfig;
+use crate::http::{RequestHead, ResponseHead};
+use flate2::write::GzEncoder;
+use log::warn;
+use std::io::{self, Write};
+
+const DEFAULT_MIN_BYTES: usize = 1024;
+// Responses are compressed in memory, so larger ones are relayed as they are.
+const MAX_COMPRESSED_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_CONTENT_TYPES: [&str; 6] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/*+json",
+    "image/svg+xml",
+];
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "br" | "brotli" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    // The content coding as named in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(body)?;
+                Ok(encoder.into_inner())
+            }
+            Encoding::Zstd => zstd::encode_all(body, ZSTD_LEVEL),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Which responses a route compresses, and with which encodings in order of
+// preference.
+pub struct CompressionPolicy {
+    encodings: Vec<Encoding>,
+    content_types: Vec<String>,
+    min_bytes: usize,
+}
+
+// An encoding agreed with a client, to be applied to responses that qualify.
+#[derive(Clone, Copy)]
+pub struct Negotiated<'a> {
+    policy: &'a CompressionPolicy,
+    pub encoding: Encoding,
+}
+
+impl CompressionPolicy {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let encodings = match &config.encodings {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| {
+                    let encoding = Encoding::parse(name);
+                    if encoding.is_none() {
+                        warn!("Ignoring unsupported compression encoding {}", name);
+                    }
+                    encoding
+                })
+                .collect(),
+            None => Encoding::ALL.to_vec(),
+        };
+        CompressionPolicy {
+            encodings,
+            content_types: config
+                .content_types
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONTENT_TYPES.map(str::to_string).to_vec()),
+            min_bytes: config.min_bytes.unwrap_or(DEFAULT_MIN_BYTES),
+        }
+    }
+
+    // Picks the encoding the client weighs highest in `Accept-Encoding`,
+    // preferring the route's order among equals.
+    pub fn negotiate(&self, request: &RequestHead) -> Option<Negotiated<'_>> {
+        if request.method.eq_ignore_ascii_case("HEAD") || request.headers.get("Range").is_some() {
+            return None;
+        }
+        let accepted: Vec<(String, f32)> = request
+            .headers
+            .get_all("Accept-Encoding")
+            .flat_map(|value| value.split(','))
+            .filter_map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next()?.trim().to_lowercase();
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!name.is_empty()).then_some((name, quality))
+            })
+            .collect();
+        let quality = |encoding: &Encoding| {
+            let named = accepted.iter().find(|(name, _)| name == encoding.as_str());
+            let wildcard = accepted.iter().find(|(name, _)| name == "*");
+            named.or(wildcard).map_or(0.0, |(_, quality)| *quality)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in &self.encodings {
+            let q = quality(encoding);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| Negotiated {
+            policy: self,
+            encoding,
+        })
+    }
+
+    fn allows_content_type(&self, head: &ResponseHead) -> bool {
+        let Some(content_type) = head.headers.get("Content-Type") else {
+            return false;
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        self.content_types.iter().any(|allowed| {
+            let allowed = allowed.to_lowercase();
+            match allowed.split_once('*') {
+                Some((prefix, suffix)) => {
+                    media_type.len() >= prefix.len() + suffix.len()
+                        && media_type.starts_with(prefix)
+                        && media_type.ends_with(suffix)
+                }
+                None => media_type == allowed,
+            }
+        })
+    }
+}
+
+impl Negotiated<'_> {
+    // Whether a response qualifies for compression. Its body length is taken
+    // from `Content-Length` unless given.
+    pub fn applies_to(&self, head: &ResponseHead, body_length: Option<usize>) -> bool {
+        if !matches!(head.status, 200 | 203 | 404 | 410) {
+            return false;
+        }
+        let encoded = head.headers.get("Transfer-Encoding").is_some()
+            || head
+                .headers
+                .get("Content-Encoding")
+                .is_some_and(|encoding| !encoding.trim().eq_ignore_ascii_case("identity"));
+        let no_transform = head
+            .headers
+            .get_all("Cache-Control")
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        if encoded || no_transform || !self.policy.allows_content_type(head) {
+            return false;
+        }
+        let length = body_length.or_else(|| {
+            head.headers
+                .get("Content-Length")
+                .and_then(|length| length.trim().parse().ok())
+        });
+        length
+            .is_some_and(|length| length >= self.policy.min_bytes && length <= MAX_COMPRESSED_BYTES)
+    }
+
+    // The compressed response, or None when compressing fails or does not make
+    // the body smaller.
+    pub fn compress(&self, head: &ResponseHead, body: &[u8]) -> Option<(ResponseHead, Vec<u8>)> {
+        let compressed = match self.encoding.encode(body) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                warn!(
+                    "Failed to compress response with {}: {}",
+                    self.encoding.as_str(),
+                    e
+                );
+                return None;
+            }
+        };
+        if compressed.len() >= body.len() {
+            return None;
+        }
+
+        let mut head = head.clone();
+        head.headers.set("Content-Encoding", self.encoding.as_str());
+        head.headers
+            .set("Content-Length", &compressed.len().to_string());
+        let varies = head
+            .headers
+            .get_all("Vary")
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim().eq_ignore_ascii_case("Accept-Encoding"));
+        if !varies {
+            head.headers.append("Vary", "Accept-Encoding");
+        }
+        // The compressed body is no longer byte-for-byte what a strong
+        // validator describes.
+        if let Some(etag) = head.headers.get("ETag").map(str::to_string) {
+            if !etag.starts_with("W/") {
+                head.headers.set("ETag", &format!("W/{}", etag));
+            }
+        }
+        Some((head, compressed))
+    }
+}
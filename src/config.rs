@@ -6,6 +6,7 @@ pub struct Config {
     pub route: Vec<Route>,
     pub retry_strategy: RetryStrategyConfig,
     pub rate_limiting: Option<RateLimitingConfig>,
+    pub health_events: Option<HealthEventsConfig>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -28,10 +29,13 @@ pub enum StrategyType {
     GeometricBackoff,
     HarmonicBackoff,
     JitterBackoff,
+    DecorrelatedJitter,
+    FullJitter,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct RateLimitingConfig {
+    pub name: Option<String>,
     pub strategy: String,
     pub limit: u32,
     pub window_size_seconds: u64,
@@ -40,6 +44,18 @@ pub struct RateLimitingConfig {
     pub capacity: Option<usize>,
     pub leak_rate_seconds: Option<u64>,
     pub granularity_seconds: Option<u64>,
+    pub key: Option<Vec<String>>,
+    pub jwt_secret: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HealthEventsConfig {
+    pub webhook_urls: Option<Vec<String>>,
+    pub webhook_timeout_seconds: Option<u64>,
+    pub flap_penalty: Option<f64>,
+    pub flap_suppress_threshold: Option<f64>,
+    pub flap_reuse_threshold: Option<f64>,
+    pub flap_half_life_seconds: Option<u64>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -54,6 +70,199 @@ pub struct RetryStrategyConfig {
     pub multiplier: Option<f64>,
     pub increment_step_millis: Option<u64>,
     pub step_increment_millis: Option<u64>,
+    pub delay_floor_millis: Option<u64>,
+    pub delay_cap_millis: Option<u64>,
+    pub jitter_percent: Option<f64>,
+    pub deadline_millis: Option<u64>,
+    pub connect_timeout_millis: Option<u64>,
+}
+
+// Per-route overrides applied on top of the global `retry_strategy` block.
+#[derive(Deserialize, Clone)]
+pub struct RetryStrategyOverride {
+    pub strategy_type: Option<StrategyType>,
+    pub max_attempts: Option<usize>,
+    pub initial_delay_millis: Option<u64>,
+    pub max_delay_secs: Option<u64>,
+    pub multiplier: Option<f64>,
+    pub timeout_secs: Option<u64>,
+    pub connect_timeout_millis: Option<u64>,
+    pub delay_cap_millis: Option<u64>,
+    pub jitter_percent: Option<f64>,
+    pub deadline_millis: Option<u64>,
+}
+
+impl RetryStrategyOverride {
+    pub fn apply_to(&self, base: &RetryStrategyConfig) -> RetryStrategyConfig {
+        let mut config = base.clone();
+        if let Some(strategy_type) = &self.strategy_type {
+            config.strategy_type = strategy_type.clone();
+        }
+        config.max_attempts = self.max_attempts.unwrap_or(config.max_attempts);
+        config.initial_delay_millis = self
+            .initial_delay_millis
+            .unwrap_or(config.initial_delay_millis);
+        config.max_delay_secs = self.max_delay_secs.unwrap_or(config.max_delay_secs);
+        config.timeout_secs = self.timeout_secs.unwrap_or(config.timeout_secs);
+        config.multiplier = self.multiplier.or(config.multiplier);
+        config.connect_timeout_millis = self
+            .connect_timeout_millis
+            .or(config.connect_timeout_millis);
+        config.delay_cap_millis = self.delay_cap_millis.or(config.delay_cap_millis);
+        config.jitter_percent = self.jitter_percent.or(config.jitter_percent);
+        config.deadline_millis = self.deadline_millis.or(config.deadline_millis);
+        config
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ResourceEndpoints {
+    List(Vec<String>),
+    Map(HashMap<String, String>),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ResourceCheckConfig {
+    pub interval_seconds: Option<u64>,
+    pub timeout_seconds: Option<u64>,
+    pub max_staleness_seconds: Option<u64>,
+    pub cpu_weight: Option<f64>,
+    pub memory_weight: Option<f64>,
+    pub score_fields: Option<HashMap<String, f64>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SessionAffinityConfig {
+    pub cookie_name: Option<String>,
+    pub secret: Option<String>,
+    pub max_age_seconds: Option<u64>,
+    pub path: Option<String>,
+    pub secure: Option<bool>,
+    pub http_only: Option<bool>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SlowStartConfig {
+    pub window_seconds: u64,
+    pub min_weight_percent: Option<f64>,
+    pub aggression: Option<f64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OutlierDetectionConfig {
+    pub consecutive_failures: Option<u32>,
+    pub base_ejection_seconds: Option<u64>,
+    pub max_ejection_seconds: Option<u64>,
+    pub max_ejection_percent: Option<f64>,
+}
+
+// A health check endpoint is either a URL (`http://`, `tcp://` or `grpc://`)
+// or a table selecting the probe protocol explicitly.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum HealthCheckTarget {
+    Url(String),
+    Probe(HealthProbeConfig),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HealthProbeConfig {
+    pub protocol: String,
+    pub address: Option<String>,
+    pub url: Option<String>,
+    pub send: Option<String>,
+    pub expect: Option<String>,
+    pub service: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    pub interval_seconds: Option<u64>,
+    pub timeout_seconds: Option<u64>,
+    pub jitter_millis: Option<u64>,
+    pub expected_statuses: Option<Vec<String>>,
+    pub body_contains: Option<String>,
+    pub body_regex: Option<String>,
+    pub json_path: Option<String>,
+    pub json_value: Option<serde_json::Value>,
+    pub headers: Option<HashMap<String, String>>,
+    pub healthy_threshold: Option<u32>,
+    pub unhealthy_threshold: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    pub failure_ratio: Option<f64>,
+    pub consecutive_failures: Option<u32>,
+    pub minimum_requests: Option<usize>,
+    pub window_seconds: Option<u64>,
+    pub open_seconds: Option<u64>,
+    pub half_open_probes: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RequestRetryConfig {
+    pub max_retries: Option<usize>,
+    pub methods: Option<Vec<String>>,
+    pub retry_on: Option<Vec<String>>,
+    pub retry_statuses: Option<Vec<u16>>,
+    pub max_body_bytes: Option<usize>,
+    pub per_try_timeout_seconds: Option<u64>,
+    pub backoff: Option<RetryStrategyConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RetryBudgetConfig {
+    pub retry_percent: Option<f64>,
+    pub min_retries: Option<usize>,
+    pub window_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HedgingConfig {
+    pub percentile: Option<f64>,
+    pub min_delay_millis: Option<u64>,
+    pub initial_delay_millis: Option<u64>,
+    pub methods: Option<Vec<String>>,
+    pub budget_percent: Option<f64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TimeoutsConfig {
+    pub client_header_millis: Option<u64>,
+    pub client_body_millis: Option<u64>,
+    pub upstream_connect_millis: Option<u64>,
+    pub upstream_first_byte_millis: Option<u64>,
+    pub idle_millis: Option<u64>,
+    pub total_millis: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CacheRuleConfig {
+    pub path: Option<String>,
+    pub path_regex: Option<String>,
+    pub methods: Option<Vec<String>>,
+    pub query_include: Option<Vec<String>>,
+    pub query_exclude: Option<Vec<String>>,
+    pub ttl_seconds: Option<u64>,
+    pub ttl_override: Option<bool>,
+    pub bypass_cookies: Option<Vec<String>>,
+    pub bypass_headers: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DiskCacheConfig {
+    pub directory: String,
+    pub max_bytes: usize,
+    pub promote_after_hits: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CompressionConfig {
+    pub encodings: Option<Vec<String>>,
+    pub content_types: Option<Vec<String>>,
+    pub min_bytes: Option<usize>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -65,15 +274,40 @@ pub struct Route {
     pub timeout_seconds: u64,
     pub balance_strategy: String,
     pub max_requests_per_target: Option<usize>,
-    pub resource_endpoints: Option<Vec<String>>,
+    pub resource_endpoints: Option<ResourceEndpoints>,
+    pub resource_check: Option<ResourceCheckConfig>,
     pub cache_enabled_endpoints: Option<Vec<String>>,
+    pub cache_rules: Option<Vec<CacheRuleConfig>>,
     pub cache_ttl_seconds: Option<u64>,
+    pub cache_ttl_override: Option<bool>,
+    pub cache_stale_while_revalidate_seconds: Option<u64>,
+    pub cache_stale_if_error_seconds: Option<u64>,
     pub cache_capacity: Option<usize>,
-    pub health_check_endpoints: Option<HashMap<String, String>>,
+    pub cache_max_bytes: Option<usize>,
+    pub cache_max_entry_bytes: Option<usize>,
+    pub cache_shards: Option<usize>,
+    pub cache_disk: Option<DiskCacheConfig>,
+    pub cache_surrogate_key_header: Option<String>,
+    pub cache_purge_networks: Option<Vec<String>>,
+    pub compression: Option<CompressionConfig>,
+    pub rate_limits: Option<Vec<RateLimitingConfig>>,
+    pub health_check_endpoints: Option<HashMap<String, HealthCheckTarget>>,
+    pub health_check: Option<HealthCheckConfig>,
     pub request_modification_rules: Option<Vec<RequestModificationRule>>,
+    pub session_affinity: Option<SessionAffinityConfig>,
+    pub slow_start: Option<SlowStartConfig>,
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub metrics_path: Option<String>,
+    pub request_retry: Option<RequestRetryConfig>,
+    pub retry_budget: Option<RetryBudgetConfig>,
+    pub hedging: Option<HedgingConfig>,
+    pub retry_strategy: Option<RetryStrategyOverride>,
+    pub timeouts: Option<TimeoutsConfig>,
 }
 
 //The resource endpoint might return data like the following JSON, which your load balancer would need to parse: {
 //  "cpu_usage_percent": 20.5,
 // "memory_usage_percent": 55.3
 //}
+//Other numeric fields, including nested ones via dotted paths, can be weighted with `resource_check.score_fields`.
@@ -32,7 +32,7 @@ impl ConfigManager {
             let should_reload = {
                 let last_modified_lock = self.last_modified.read().await;
                 let metadata = fs::metadata(&config_path).await.ok();
-                metadata.map_or(false, |m| m.modified().ok() > Some(*last_modified_lock))
+                metadata.is_some_and(|m| m.modified().ok() > Some(*last_modified_lock))
             };
 
             if should_reload {
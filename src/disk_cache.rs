@@ -0,0 +1,375 @@
+use crate::cache::{CachedResponse, Lifetime, Purge, Variants};
+use crate::cache_policy::has_validators;
+use crate::config::DiskCacheConfig;
+use crate::http::{Headers, ResponseHead};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+
+const INDEX_FILE: &str = "index.json";
+const DEFAULT_PROMOTE_AFTER_HITS: u32 = 2;
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+// A cached response on disk. The response itself is in `file`, written as it
+// would be sent: head, then body.
+#[derive(Clone, Serialize, Deserialize)]
+struct DiskRecord {
+    key: String,
+    variant_key: String,
+    vary: Vec<String>,
+    file: String,
+    size: usize,
+    lifetime: Lifetime,
+    revalidatable: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    hits: u32,
+}
+
+impl DiskRecord {
+    fn is_retained(&self, now: SystemTime) -> bool {
+        self.lifetime.is_retained(now, self.revalidatable)
+    }
+}
+
+// The persisted index, least recently used first.
+#[derive(Serialize, Deserialize)]
+struct DiskIndex {
+    entries: Vec<DiskRecord>,
+}
+
+#[derive(Default)]
+struct DiskState {
+    records: Variants<DiskRecord>,
+    // Whether the index has changed since it was last persisted.
+    dirty: bool,
+}
+
+pub struct DiskHit {
+    pub response: CachedResponse,
+    pub lifetime: Lifetime,
+    pub vary: Vec<String>,
+    pub variant_key: String,
+    // Whether the entry has been read often enough to be kept in memory too.
+    pub promote: bool,
+}
+
+// The on-disk tier of the response cache: one file per response, an index
+// kept in memory and persisted to the directory periodically, and LRU
+// eviction within a byte budget.
+pub struct DiskCache {
+    directory: PathBuf,
+    max_bytes: usize,
+    promote_after_hits: u32,
+    state: Mutex<DiskState>,
+    next_file: AtomicU64,
+}
+
+impl DiskCache {
+    // Opens the cache directory, restoring the index persisted by a previous
+    // run. Entries whose files are missing are dropped, and files no entry
+    // refers to are deleted.
+    pub fn open(config: &DiskCacheConfig) -> io::Result<Self> {
+        let directory = PathBuf::from(&config.directory);
+        std::fs::create_dir_all(&directory)?;
+        let records = match std::fs::read(directory.join(INDEX_FILE)) {
+            Ok(bytes) => match serde_json::from_slice::<DiskIndex>(&bytes) {
+                Ok(index) => index.entries,
+                Err(e) => {
+                    warn!(
+                        "Ignoring unreadable cache index in {}: {}",
+                        config.directory, e
+                    );
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let now = SystemTime::now();
+        let mut state = DiskState::default();
+        for record in records {
+            if !record.is_retained(now) || !directory.join(&record.file).is_file() {
+                continue;
+            }
+            let (key, variant_key, vary, size) = (
+                record.key.clone(),
+                record.variant_key.clone(),
+                record.vary.clone(),
+                record.size,
+            );
+            state.records.insert(key, variant_key, vary, record, size);
+        }
+        state
+            .records
+            .make_room(0, 0, config.max_bytes, |record| record.is_retained(now));
+
+        let known: HashSet<&str> = state
+            .records
+            .values()
+            .map(|record| record.file.as_str())
+            .collect();
+        // Responses left behind by earlier runs are removed, but nothing else
+        // that happens to share the directory.
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            if name.is_some_and(|name| is_response_file(name) && !known.contains(name)) {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        state.dirty = true;
+
+        Ok(DiskCache {
+            directory,
+            max_bytes: config.max_bytes,
+            promote_after_hits: config
+                .promote_after_hits
+                .unwrap_or(DEFAULT_PROMOTE_AFTER_HITS),
+            state: Mutex::new(state),
+            next_file: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn get(
+        &self,
+        key: &str,
+        request_headers: &Headers,
+        encoding: Option<&str>,
+    ) -> Option<DiskHit> {
+        let now = SystemTime::now();
+        let found = {
+            let mut state = self.state.lock().unwrap();
+            let (variant_key, record) = state.records.get_mut(key, request_headers, encoding)?;
+            let found = if record.is_retained(now) {
+                record.hits += 1;
+                let promote = record.hits >= self.promote_after_hits;
+                Ok((
+                    variant_key,
+                    record.file.clone(),
+                    record.vary.clone(),
+                    record.lifetime,
+                    promote,
+                ))
+            } else {
+                Err(state.records.remove(&variant_key))
+            };
+            state.dirty = true;
+            found
+        };
+        let (variant_key, file, vary, lifetime, promote) = match found {
+            Ok(found) => found,
+            Err(expired) => {
+                self.remove_files(expired).await;
+                return None;
+            }
+        };
+
+        match self.read_response(&file).await {
+            Ok(response) => Some(DiskHit {
+                response,
+                lifetime,
+                vary,
+                variant_key,
+                promote,
+            }),
+            Err(e) => {
+                warn!("Dropping unreadable cache file {}: {}", file, e);
+                let mut state = self.state.lock().unwrap();
+                let (_, record) = state.records.get_mut(key, request_headers, encoding)?;
+                if record.file == file {
+                    state.records.remove(&variant_key);
+                    state.dirty = true;
+                }
+                None
+            }
+        }
+    }
+
+    // Writes a response to disk, evicting the least recently used entries to
+    // stay within the byte budget. A response larger than the whole budget
+    // is not stored and drops the variant it would have replaced.
+    pub async fn put(
+        &self,
+        key: &str,
+        vary: &[String],
+        variant_key: &str,
+        response: &CachedResponse,
+        lifetime: Lifetime,
+        tags: &[String],
+    ) {
+        let mut contents = response.head.to_bytes();
+        contents.extend_from_slice(&response.body);
+        let size = contents.len();
+
+        if size > self.max_bytes {
+            let replaced = self.state.lock().unwrap().records.remove(variant_key);
+            self.remove_files(replaced).await;
+            return;
+        }
+        let file = self.file_name(variant_key);
+        if let Err(e) = tokio::fs::write(self.directory.join(&file), &contents).await {
+            warn!("Failed to write cache file {}: {}", file, e);
+            return;
+        }
+
+        let record = DiskRecord {
+            key: key.to_string(),
+            variant_key: variant_key.to_string(),
+            vary: vary.to_vec(),
+            file,
+            size,
+            lifetime,
+            revalidatable: has_validators(&response.head),
+            tags: tags.to_vec(),
+            hits: 0,
+        };
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            state.dirty = true;
+            let now = SystemTime::now();
+            let mut removed: Vec<DiskRecord> =
+                state.records.remove(variant_key).into_iter().collect();
+            removed.extend(
+                state
+                    .records
+                    .make_room(size, 0, self.max_bytes, |record| record.is_retained(now)),
+            );
+            state.records.insert(
+                key.to_string(),
+                variant_key.to_string(),
+                vary.to_vec(),
+                record,
+                size,
+            );
+            removed
+        };
+        self.remove_files(removed).await;
+    }
+
+    // The vary header names and lifetime of the unencoded response stored
+    // for a request.
+    pub fn identity(
+        &self,
+        key: &str,
+        request_headers: &Headers,
+    ) -> Option<(Vec<String>, Lifetime)> {
+        let mut state = self.state.lock().unwrap();
+        let (_, record) = state.records.get_mut(key, request_headers, None)?;
+        Some((record.vary.clone(), record.lifetime))
+    }
+
+    pub async fn remove(&self, variant_keys: &[String]) {
+        let removed: Vec<DiskRecord> = {
+            let mut state = self.state.lock().unwrap();
+            let removed: Vec<DiskRecord> = variant_keys
+                .iter()
+                .filter_map(|variant_key| state.records.remove(variant_key))
+                .collect();
+            state.dirty |= !removed.is_empty();
+            removed
+        };
+        self.remove_files(removed).await;
+    }
+
+    // Removes matching entries, returning their variant keys.
+    pub async fn purge(&self, purge: &Purge) -> Vec<String> {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let removed = state
+                .records
+                .remove_matching(|key, record| purge.matches(key, &record.tags));
+            state.dirty |= !removed.is_empty();
+            removed
+        };
+        let variant_keys = removed
+            .iter()
+            .map(|(variant_key, _)| variant_key.clone())
+            .collect();
+        self.remove_files(removed.into_iter().map(|(_, record)| record))
+            .await;
+        variant_keys
+    }
+
+    // Writes the index if it has changed since it was last written.
+    pub async fn persist(&self) -> io::Result<()> {
+        let index = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            DiskIndex {
+                entries: state.records.values().cloned().collect(),
+            }
+        };
+        let contents = serde_json::to_vec(&index).map_err(io::Error::other)?;
+        let temporary = self.directory.join(format!("{}.tmp", INDEX_FILE));
+        tokio::fs::write(&temporary, contents).await?;
+        tokio::fs::rename(&temporary, self.directory.join(INDEX_FILE)).await
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut persist_interval = interval(PERSIST_INTERVAL);
+        loop {
+            persist_interval.tick().await;
+            if let Err(e) = self.persist().await {
+                warn!(
+                    "Failed to persist cache index in {}: {}",
+                    self.directory.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    async fn read_response(&self, file: &str) -> io::Result<CachedResponse> {
+        let contents = tokio::fs::read(self.directory.join(file)).await?;
+        let mut reader = contents.as_slice();
+        let head = ResponseHead::read_from(&mut reader).await?;
+        Ok(CachedResponse {
+            head,
+            body: reader.to_vec(),
+        })
+    }
+
+    async fn remove_files(&self, records: impl IntoIterator<Item = DiskRecord>) {
+        for record in records {
+            let _ = tokio::fs::remove_file(self.directory.join(&record.file)).await;
+        }
+    }
+
+    // A name no other file shares, including those left by earlier runs. See
+    // `is_response_file` for its shape.
+    fn file_name(&self, variant_key: &str) -> String {
+        let digest = hex::encode(Sha256::digest(variant_key.as_bytes()));
+        let written_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = self.next_file.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{:x}-{:x}", &digest[..16], written_at, sequence)
+    }
+}
+
+// Whether `name` is shaped like the names `DiskCache::file_name` gives
+// responses: sixteen hex digits of the key's digest, the time written and a
+// sequence number, separated by dashes.
+fn is_response_file(name: &str) -> bool {
+    let is_hex = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_hexdigit());
+    let mut parts = name.split('-');
+    matches!(
+        (parts.next(), parts.next(), parts.next(), parts.next()),
+        (Some(digest), Some(written_at), Some(sequence), None)
+            if digest.len() == 16 && is_hex(digest) && is_hex(written_at) && is_hex(sequence)
+    )
+}
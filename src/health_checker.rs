@@ -1,39 +1,527 @@
+use crate::config::{HealthCheckConfig, HealthCheckTarget, HealthProbeConfig};
+use crate::health_events::HealthEvent;
 use futures::future::join_all;
+use log::warn;
+use rand::Rng;
+use regex::Regex;
 use reqwest::Client;
+use serde_json::Value;
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const DEFAULT_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_TIMEOUT_SECONDS: u64 = 5;
+const MAX_EXPECT_READ_BYTES: usize = 4096;
+const GRPC_SERVING: u64 = 1;
+
+enum HealthProbe {
+    Http(String),
+    Tcp {
+        address: String,
+        send: Option<Vec<u8>>,
+        expect: Option<Vec<u8>>,
+    },
+    Grpc {
+        address: String,
+        service: String,
+    },
+}
+
+impl HealthProbe {
+    fn from_target(addr: &str, target: &HealthCheckTarget) -> Option<Self> {
+        match target {
+            HealthCheckTarget::Url(url) => {
+                if let Some(address) = url.strip_prefix("tcp://") {
+                    Some(HealthProbe::Tcp {
+                        address: address.to_string(),
+                        send: None,
+                        expect: None,
+                    })
+                } else if let Some(rest) = url.strip_prefix("grpc://") {
+                    let (address, service) = rest.split_once('/').unwrap_or((rest, ""));
+                    Some(HealthProbe::Grpc {
+                        address: address.to_string(),
+                        service: service.to_string(),
+                    })
+                } else {
+                    Some(HealthProbe::Http(url.clone()))
+                }
+            }
+            HealthCheckTarget::Probe(probe) => Self::from_probe_config(addr, probe),
+        }
+    }
+
+    fn from_probe_config(addr: &str, probe: &HealthProbeConfig) -> Option<Self> {
+        let address = probe.address.clone().unwrap_or_else(|| addr.to_string());
+        match probe.protocol.to_lowercase().as_str() {
+            "http" => match &probe.url {
+                Some(url) => Some(HealthProbe::Http(url.clone())),
+                None => {
+                    warn!("HTTP health check for {} requires a url", addr);
+                    None
+                }
+            },
+            "tcp" => Some(HealthProbe::Tcp {
+                address,
+                send: probe.send.as_ref().map(|s| s.as_bytes().to_vec()),
+                expect: probe.expect.as_ref().map(|s| s.as_bytes().to_vec()),
+            }),
+            "grpc" => Some(HealthProbe::Grpc {
+                address,
+                service: probe.service.clone().unwrap_or_default(),
+            }),
+            other => {
+                warn!("Unsupported health check protocol for {}: {}", addr, other);
+                None
+            }
+        }
+    }
+}
+
+pub struct HealthCheckPolicy {
+    interval: Duration,
+    timeout: Duration,
+    jitter: Duration,
+    expected_statuses: Vec<RangeInclusive<u16>>,
+    body_contains: Option<String>,
+    body_regex: Option<Regex>,
+    json_path: Option<String>,
+    json_value: Option<Value>,
+    headers: HashMap<String, String>,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+}
+
+impl HealthCheckPolicy {
+    pub fn new(config: Option<&HealthCheckConfig>) -> Self {
+        let expected_statuses = config
+            .and_then(|c| c.expected_statuses.as_ref())
+            .map(|statuses| {
+                statuses
+                    .iter()
+                    .filter_map(|s| parse_status_range(s))
+                    .collect()
+            })
+            .unwrap_or_else(|| vec![200..=399]);
+        let body_regex = config
+            .and_then(|c| c.body_regex.as_ref())
+            .and_then(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    warn!(
+                        "Ignoring invalid health check body_regex {}: {}",
+                        pattern, e
+                    );
+                    None
+                }
+            });
+
+        HealthCheckPolicy {
+            interval: Duration::from_secs(
+                config
+                    .and_then(|c| c.interval_seconds)
+                    .unwrap_or(DEFAULT_INTERVAL_SECONDS),
+            ),
+            timeout: Duration::from_secs(
+                config
+                    .and_then(|c| c.timeout_seconds)
+                    .unwrap_or(DEFAULT_TIMEOUT_SECONDS),
+            ),
+            jitter: Duration::from_millis(config.and_then(|c| c.jitter_millis).unwrap_or(0)),
+            expected_statuses,
+            body_contains: config.and_then(|c| c.body_contains.clone()),
+            body_regex,
+            json_path: config.and_then(|c| c.json_path.clone()),
+            json_value: config.and_then(|c| c.json_value.clone()),
+            headers: config.and_then(|c| c.headers.clone()).unwrap_or_default(),
+            healthy_threshold: config.and_then(|c| c.healthy_threshold).unwrap_or(1).max(1),
+            unhealthy_threshold: config
+                .and_then(|c| c.unhealthy_threshold)
+                .unwrap_or(1)
+                .max(1),
+        }
+    }
+
+    // Delay until the next round of probes, spread by a random jitter so that
+    // several proxies do not probe the same backends in lockstep.
+    pub fn next_delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+        self.interval + jitter
+    }
+
+    fn needs_body(&self) -> bool {
+        self.body_contains.is_some() || self.body_regex.is_some() || self.json_path.is_some()
+    }
+
+    fn body_matches(&self, body: &str) -> bool {
+        if let Some(needle) = &self.body_contains {
+            if !body.contains(needle.as_str()) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.body_regex {
+            if !regex.is_match(body) {
+                return false;
+            }
+        }
+        if let Some(path) = &self.json_path {
+            let Ok(document) = serde_json::from_str::<Value>(body) else {
+                return false;
+            };
+            let Some(found) = lookup_json(&document, path) else {
+                return false;
+            };
+            return match &self.json_value {
+                Some(expected) => found == expected,
+                None => !matches!(found, Value::Null | Value::Bool(false)),
+            };
+        }
+        true
+    }
+}
+
+impl Default for HealthCheckPolicy {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+// Accepts "200", "200-299" and "2xx".
+fn parse_status_range(value: &str) -> Option<RangeInclusive<u16>> {
+    let value = value.trim();
+    let range = if let Some((start, end)) = value.split_once('-') {
+        Some(start.trim().parse().ok()?..=end.trim().parse().ok()?)
+    } else if let Some(class) = value.strip_suffix("xx") {
+        let class: u16 = class.parse().ok().filter(|class| (1..=5).contains(class))?;
+        Some(class * 100..=class * 100 + 99)
+    } else {
+        value.parse().ok().map(|status| status..=status)
+    };
+    if range.is_none() {
+        warn!("Ignoring invalid health check status range: {}", value);
+    }
+    range
+}
+
+fn lookup_json<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |current, key| match key.parse::<usize>() {
+            Ok(index) if current.is_array() => current.get(index),
+            _ => current.get(key),
+        })
+}
+
+pub struct ProbeResult {
+    pub healthy: bool,
+    pub reason: String,
+    pub latency: Duration,
+}
+
+// Applies rise/fall thresholds to raw probe results, so a target only changes
+// state after several consecutive probes agree.
+#[derive(Default)]
+pub struct HealthTracker {
+    targets: HashMap<String, TrackedTarget>,
+}
+
+struct TrackedTarget {
+    healthy: bool,
+    streak: u32,
+}
+
+impl HealthTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the thresholded status of every probed target together with an
+    // event for each target whose status changed.
+    pub fn apply(
+        &mut self,
+        results: HashMap<String, ProbeResult>,
+        policy: &HealthCheckPolicy,
+    ) -> (HashMap<String, bool>, Vec<HealthEvent>) {
+        let mut statuses = HashMap::new();
+        let mut events = Vec::new();
+        for (addr, result) in results {
+            // Targets start out healthy, matching how unknown targets are routed.
+            let target = self.targets.entry(addr.clone()).or_insert(TrackedTarget {
+                healthy: true,
+                streak: 0,
+            });
+            if result.healthy == target.healthy {
+                target.streak = 0;
+            } else {
+                target.streak += 1;
+                let threshold = if result.healthy {
+                    policy.healthy_threshold
+                } else {
+                    policy.unhealthy_threshold
+                };
+                if target.streak >= threshold {
+                    events.push(HealthEvent::new(
+                        &addr,
+                        target.healthy,
+                        result.healthy,
+                        result.reason,
+                        result.latency,
+                    ));
+                    target.healthy = result.healthy;
+                    target.streak = 0;
+                }
+            }
+            statuses.insert(addr, target.healthy);
+        }
+        (statuses, events)
+    }
+}
 
 pub struct HealthChecker {
     client: Client,
+    grpc_client: Client,
 }
 
-impl HealthChecker {
-    pub fn new() -> Self {
-        Self {
+impl Default for HealthChecker {
+    fn default() -> Self {
+        HealthChecker {
             client: Client::new(),
+            grpc_client: Client::builder()
+                .http2_prior_knowledge()
+                .build()
+                .unwrap_or_default(),
         }
     }
+}
+
+impl HealthChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
     pub async fn check_health(
         &self,
-        health_check_endpoints: &HashMap<String, String>,
-    ) -> HashMap<String, bool> {
+        health_check_endpoints: &HashMap<String, HealthCheckTarget>,
+        policy: &HealthCheckPolicy,
+    ) -> HashMap<String, ProbeResult> {
         let mut check_futures = Vec::new();
 
-        for (addr, endpoint) in health_check_endpoints {
-            let client = self.client.clone();
+        for (addr, target) in health_check_endpoints {
             let future = async move {
-                let is_healthy = client.get(endpoint).send().await.is_ok();
-                (addr.clone(), is_healthy)
+                let started = Instant::now();
+                let outcome = match HealthProbe::from_target(addr, target) {
+                    Some(HealthProbe::Http(url)) => self.probe_http(&url, policy).await,
+                    Some(HealthProbe::Tcp {
+                        address,
+                        send,
+                        expect,
+                    }) => probe_tcp(&address, send, expect, policy).await,
+                    Some(HealthProbe::Grpc { address, service }) => {
+                        self.probe_grpc(&address, &service, policy).await
+                    }
+                    None => Err("invalid health check configuration".to_string()),
+                };
+                let result = ProbeResult {
+                    healthy: outcome.is_ok(),
+                    reason: outcome
+                        .err()
+                        .unwrap_or_else(|| "probe succeeded".to_string()),
+                    latency: started.elapsed(),
+                };
+                (addr.clone(), result)
             };
             check_futures.push(future);
         }
 
         let results = join_all(check_futures).await;
         let mut statuses = HashMap::new();
-        for (addr, is_healthy) in results {
-            statuses.insert(addr, is_healthy);
+        for (addr, result) in results {
+            statuses.insert(addr, result);
         }
 
         statuses
     }
+
+    async fn probe_http(&self, endpoint: &str, policy: &HealthCheckPolicy) -> Result<(), String> {
+        let mut request = self.client.get(endpoint).timeout(policy.timeout);
+        for (key, value) in &policy.headers {
+            request = request.header(key, value);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("request failed: {}", e))?;
+
+        let status = response.status().as_u16();
+        if !policy
+            .expected_statuses
+            .iter()
+            .any(|range| range.contains(&status))
+        {
+            return Err(format!("unexpected status {}", status));
+        }
+        if !policy.needs_body() {
+            return Ok(());
+        }
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("failed to read body: {}", e))?;
+        if policy.body_matches(&body) {
+            Ok(())
+        } else {
+            Err("response body did not match".to_string())
+        }
+    }
+
+    // Speaks the standard grpc.health.v1 protocol over cleartext HTTP/2.
+    async fn probe_grpc(
+        &self,
+        address: &str,
+        service: &str,
+        policy: &HealthCheckPolicy,
+    ) -> Result<(), String> {
+        let base = if address.contains("://") {
+            address.to_string()
+        } else {
+            format!("http://{}", address)
+        };
+        let url = format!("{}/grpc.health.v1.Health/Check", base.trim_end_matches('/'));
+
+        let mut message = Vec::new();
+        if !service.is_empty() {
+            message.push(0x0a);
+            encode_varint(service.len() as u64, &mut message);
+            message.extend_from_slice(service.as_bytes());
+        }
+        let mut body = vec![0];
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(&message);
+
+        let response = self
+            .grpc_client
+            .post(url)
+            .timeout(policy.timeout)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("unexpected status {}", response.status().as_u16()));
+        }
+        // Errors are reported in the headers of trailers-only responses.
+        if let Some(status) = response.headers().get("grpc-status") {
+            if status != "0" {
+                return Err(format!("grpc-status {:?}", status));
+            }
+        }
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| format!("failed to read body: {}", e))?;
+        match decode_serving_status(&bytes) {
+            Some(GRPC_SERVING) => Ok(()),
+            Some(status) => Err(format!("serving status {}", status)),
+            None => Err("missing serving status".to_string()),
+        }
+    }
+}
+
+async fn probe_tcp(
+    address: &str,
+    send: Option<Vec<u8>>,
+    expect: Option<Vec<u8>>,
+    policy: &HealthCheckPolicy,
+) -> Result<(), String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(address).await?;
+        if let Some(payload) = &send {
+            stream.write_all(payload).await?;
+        }
+        let Some(expected) = &expect else {
+            return Ok(true);
+        };
+        let mut received = Vec::new();
+        let mut buf = [0; 512];
+        while received.len() < MAX_EXPECT_READ_BYTES {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+            if received
+                .windows(expected.len().max(1))
+                .any(|window| window == expected.as_slice())
+            {
+                return Ok(true);
+            }
+        }
+        Ok::<_, std::io::Error>(false)
+    };
+    match timeout(policy.timeout, exchange).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Ok(false)) => Err("expected response not received".to_string()),
+        Ok(Err(e)) => Err(format!("connection failed: {}", e)),
+        Err(_) => Err("probe timed out".to_string()),
+    }
+}
+
+fn encode_varint(mut value: u64, buffer: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn decode_varint(bytes: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*position)?;
+        *position += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// The position `length` bytes on, or None when that is past the end, so that
+// a length sent by the target cannot run off the message.
+fn skip(bytes: &[u8], position: usize, length: usize) -> Option<usize> {
+    position
+        .checked_add(length)
+        .filter(|end| *end <= bytes.len())
+}
+
+// Extracts `HealthCheckResponse.status` from a length-prefixed gRPC message.
+fn decode_serving_status(frame: &[u8]) -> Option<u64> {
+    let message = frame.get(5..)?;
+    let mut position = 0;
+    while position < message.len() {
+        let tag = decode_varint(message, &mut position)?;
+        match (tag >> 3, tag & 0x7) {
+            (1, 0) => return decode_varint(message, &mut position),
+            (_, 0) => {
+                decode_varint(message, &mut position)?;
+            }
+            (_, 1) => position = skip(message, position, 8)?,
+            (_, 2) => {
+                let length = decode_varint(message, &mut position)?;
+                position = skip(message, position, usize::try_from(length).ok()?)?;
+            }
+            (_, 5) => position = skip(message, position, 4)?,
+            _ => return None,
+        }
+    }
+    None
 }
@@ -0,0 +1,204 @@
+use crate::config::HealthEventsConfig;
+use log::{info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, Mutex};
+
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+const DEFAULT_WEBHOOK_TIMEOUT_SECONDS: u64 = 5;
+const DEFAULT_FLAP_PENALTY: f64 = 1000.0;
+const DEFAULT_SUPPRESS_THRESHOLD: f64 = 3000.0;
+const DEFAULT_REUSE_THRESHOLD: f64 = 750.0;
+const DEFAULT_HALF_LIFE_SECONDS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthState {
+    Healthy,
+    Unhealthy,
+}
+
+impl From<bool> for HealthState {
+    fn from(healthy: bool) -> Self {
+        if healthy {
+            HealthState::Healthy
+        } else {
+            HealthState::Unhealthy
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthEvent {
+    pub target: String,
+    pub old_state: HealthState,
+    pub new_state: HealthState,
+    pub reason: String,
+    pub probe_latency_ms: u64,
+    pub timestamp: u64,
+}
+
+impl HealthEvent {
+    pub fn new(
+        target: &str,
+        was_healthy: bool,
+        is_healthy: bool,
+        reason: String,
+        probe_latency: Duration,
+    ) -> Self {
+        HealthEvent {
+            target: target.to_string(),
+            old_state: was_healthy.into(),
+            new_state: is_healthy.into(),
+            reason,
+            probe_latency_ms: probe_latency.as_millis() as u64,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+struct FlapState {
+    penalty: f64,
+    updated: Instant,
+    suppressed: bool,
+    // The latest event withheld while suppressed.
+    withheld: Option<HealthEvent>,
+}
+
+// Publishes health state changes to the log, to in-process subscribers and to
+// webhooks. Targets that flap are damped: every change adds a penalty that
+// decays with the configured half-life, and while a target is suppressed its
+// events are withheld until the penalty falls below the reuse threshold, when
+// the last of them is emitted.
+pub struct HealthEventBus {
+    sender: broadcast::Sender<HealthEvent>,
+    client: Client,
+    webhook_urls: Vec<String>,
+    webhook_timeout: Duration,
+    flap_penalty: f64,
+    suppress_threshold: f64,
+    reuse_threshold: f64,
+    half_life: Duration,
+    flaps: Mutex<HashMap<String, FlapState>>,
+}
+
+impl HealthEventBus {
+    pub fn new(config: Option<&HealthEventsConfig>) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        HealthEventBus {
+            sender,
+            client: Client::new(),
+            webhook_urls: config
+                .and_then(|c| c.webhook_urls.clone())
+                .unwrap_or_default(),
+            webhook_timeout: Duration::from_secs(
+                config
+                    .and_then(|c| c.webhook_timeout_seconds)
+                    .unwrap_or(DEFAULT_WEBHOOK_TIMEOUT_SECONDS),
+            ),
+            flap_penalty: config
+                .and_then(|c| c.flap_penalty)
+                .unwrap_or(DEFAULT_FLAP_PENALTY),
+            suppress_threshold: config
+                .and_then(|c| c.flap_suppress_threshold)
+                .unwrap_or(DEFAULT_SUPPRESS_THRESHOLD),
+            reuse_threshold: config
+                .and_then(|c| c.flap_reuse_threshold)
+                .unwrap_or(DEFAULT_REUSE_THRESHOLD),
+            half_life: Duration::from_secs(
+                config
+                    .and_then(|c| c.flap_half_life_seconds)
+                    .unwrap_or(DEFAULT_HALF_LIFE_SECONDS)
+                    .max(1),
+            ),
+            flaps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<HealthEvent> {
+        self.sender.subscribe()
+    }
+
+    pub async fn publish(&self, event: HealthEvent) {
+        let event = {
+            let mut flaps = self.flaps.lock().await;
+            let state = flaps.entry(event.target.clone()).or_insert(FlapState {
+                penalty: 0.0,
+                updated: Instant::now(),
+                suppressed: false,
+                withheld: None,
+            });
+            self.decay(&event.target, state);
+            state.penalty += self.flap_penalty;
+            if !state.suppressed && state.penalty > self.suppress_threshold {
+                warn!("{} is flapping; suppressing health events", event.target);
+                state.suppressed = true;
+            }
+            if state.suppressed {
+                state.withheld = Some(event);
+                return;
+            }
+            event
+        };
+        self.emit(event);
+    }
+
+    // Emits the last withheld event of every target whose penalty has decayed
+    // below the reuse threshold since it was suppressed, so that subscribers
+    // learn the state the target settled in. Called once per probe cycle.
+    pub async fn release_settled(&self) {
+        let settled = {
+            let mut flaps = self.flaps.lock().await;
+            flaps
+                .iter_mut()
+                .filter_map(|(target, state)| {
+                    self.decay(target, state);
+                    state.withheld.take_if(|_| !state.suppressed)
+                })
+                .collect::<Vec<_>>()
+        };
+        for event in settled {
+            self.emit(event);
+        }
+    }
+
+    fn decay(&self, target: &str, state: &mut FlapState) {
+        let now = Instant::now();
+        let half_lives =
+            now.duration_since(state.updated).as_secs_f64() / self.half_life.as_secs_f64();
+        state.penalty *= 0.5_f64.powf(half_lives);
+        state.updated = now;
+        if state.suppressed && state.penalty < self.reuse_threshold {
+            info!("{} stopped flapping; resuming health events", target);
+            state.suppressed = false;
+        }
+    }
+
+    fn emit(&self, event: HealthEvent) {
+        info!(
+            "Health state change: target={} {:?} -> {:?} reason=\"{}\" latency={}ms",
+            event.target, event.old_state, event.new_state, event.reason, event.probe_latency_ms
+        );
+        // Sending only fails when nobody is subscribed.
+        let _ = self.sender.send(event.clone());
+
+        for url in &self.webhook_urls {
+            let request = self
+                .client
+                .post(url)
+                .timeout(self.webhook_timeout)
+                .json(&event);
+            let url = url.clone();
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                    warn!("Failed to deliver health event to {}: {}", url, e);
+                }
+            });
+        }
+    }
+}
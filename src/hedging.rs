@@ -0,0 +1,142 @@
+use crate::config::HedgingConfig;
+use crate::metrics::Metrics;
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DEFAULT_PERCENTILE: f64 = 95.0;
+const DEFAULT_MIN_DELAY_MILLIS: u64 = 5;
+const DEFAULT_INITIAL_DELAY_MILLIS: u64 = 100;
+const DEFAULT_BUDGET_PERCENT: f64 = 10.0;
+const DEFAULT_METHODS: [&str; 3] = ["GET", "HEAD", "OPTIONS"];
+// Methods that may be sent twice without changing the outcome.
+const IDEMPOTENT_METHODS: [&str; 5] = ["GET", "HEAD", "OPTIONS", "PUT", "DELETE"];
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+const MIN_SAMPLES: usize = 20;
+const MAX_SAMPLES: usize = 512;
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+struct HedgeWindow {
+    started: Instant,
+    requests: usize,
+    hedges: usize,
+}
+
+// Sends a second copy of a slow safe request to another target. The hedge
+// delay follows a percentile of recently observed response times, and the
+// number of hedges is capped to a share of recent requests.
+pub struct HedgePolicy {
+    pub max_body_bytes: usize,
+    percentile: f64,
+    min_delay: Duration,
+    initial_delay: Duration,
+    budget_ratio: f64,
+    methods: Vec<String>,
+    latencies: Mutex<VecDeque<Duration>>,
+    window: Mutex<HedgeWindow>,
+    metrics: Arc<Metrics>,
+}
+
+impl HedgePolicy {
+    pub fn new(config: &HedgingConfig, metrics: Arc<Metrics>) -> Self {
+        let methods = match &config.methods {
+            Some(methods) => methods
+                .iter()
+                .map(|m| m.to_uppercase())
+                .filter(|m| {
+                    let idempotent = IDEMPOTENT_METHODS.contains(&m.as_str());
+                    if !idempotent {
+                        warn!("Not hedging {} requests; the method is not idempotent", m);
+                    }
+                    idempotent
+                })
+                .collect(),
+            None => DEFAULT_METHODS.iter().map(|m| m.to_string()).collect(),
+        };
+        HedgePolicy {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            percentile: config
+                .percentile
+                .unwrap_or(DEFAULT_PERCENTILE)
+                .clamp(0.0, 100.0),
+            min_delay: Duration::from_millis(
+                config.min_delay_millis.unwrap_or(DEFAULT_MIN_DELAY_MILLIS),
+            ),
+            initial_delay: Duration::from_millis(
+                config
+                    .initial_delay_millis
+                    .unwrap_or(DEFAULT_INITIAL_DELAY_MILLIS),
+            ),
+            budget_ratio: config.budget_percent.unwrap_or(DEFAULT_BUDGET_PERCENT) / 100.0,
+            methods,
+            latencies: Mutex::new(VecDeque::new()),
+            window: Mutex::new(HedgeWindow {
+                started: Instant::now(),
+                requests: 0,
+                hedges: 0,
+            }),
+            metrics,
+        }
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    // How long to wait for the first response before sending the hedge.
+    pub async fn delay(&self) -> Duration {
+        let latencies = self.latencies.lock().await;
+        if latencies.len() < MIN_SAMPLES {
+            return self.initial_delay;
+        }
+        let mut sorted: Vec<Duration> = latencies.iter().copied().collect();
+        sorted.sort();
+        let rank = (self.percentile / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+        sorted[rank].max(self.min_delay)
+    }
+
+    pub async fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().await;
+        if latencies.len() == MAX_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    pub async fn record_request(&self) {
+        let mut window = self.window.lock().await;
+        Self::roll(&mut window);
+        window.requests += 1;
+    }
+
+    // Takes one hedge from the budget, returning false when it is spent.
+    pub async fn try_acquire(&self, target: &str) -> bool {
+        let mut window = self.window.lock().await;
+        Self::roll(&mut window);
+        let allowed = ((window.requests as f64 * self.budget_ratio) as usize).max(1);
+        if window.hedges >= allowed {
+            self.metrics
+                .increment("road47_hedge_budget_exhausted_total", &[("target", target)]);
+            return false;
+        }
+        window.hedges += 1;
+        self.metrics
+            .increment("road47_hedged_requests_total", &[("target", target)]);
+        true
+    }
+
+    pub fn record_hedge_win(&self, target: &str) {
+        self.metrics
+            .increment("road47_hedge_wins_total", &[("target", target)]);
+    }
+
+    fn roll(window: &mut HedgeWindow) {
+        if window.started.elapsed() >= BUDGET_WINDOW {
+            window.started = Instant::now();
+            window.requests = 0;
+            window.hedges = 0;
+        }
+    }
+}
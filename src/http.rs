@@ -0,0 +1,242 @@
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+const MAX_HEADER_COUNT: usize = 100;
+const MAX_CHUNK_LINE_BYTES: usize = 8 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    fn write_to(&self, buffer: &mut String) {
+        for (key, value) in &self.entries {
+            buffer.push_str(key);
+            buffer.push_str(": ");
+            buffer.push_str(value);
+            buffer.push_str("\r\n");
+        }
+        buffer.push_str("\r\n");
+    }
+
+    async fn read_from<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let mut headers = Headers::default();
+        loop {
+            let line = read_line(reader).await?;
+            if line.is_empty() {
+                return Ok(headers);
+            }
+            if headers.entries.len() >= MAX_HEADER_COUNT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Too many header fields",
+                ));
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                headers.append(key.trim(), value.trim());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    pub async fn read_from<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let request_line = read_line(reader).await?;
+        let parts: Vec<&str> = request_line.split_whitespace().collect();
+        if parts.len() < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid HTTP request line",
+            ));
+        }
+        let headers = Headers::read_from(reader).await?;
+        Ok(RequestHead {
+            method: parts[0].to_string(),
+            path: parts[1].to_string(),
+            version: parts[2].to_string(),
+            headers,
+        })
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get_all("Cookie")
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = format!("{} {} {}\r\n", self.method, self.path, self.version);
+        self.headers.write_to(&mut buffer);
+        buffer.into_bytes()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub async fn read_from<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let status_line = read_line(reader).await?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default().to_string();
+        let status = parts
+            .next()
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP status line")
+            })?;
+        let reason = parts.next().unwrap_or_default().to_string();
+        let headers = Headers::read_from(reader).await?;
+        Ok(ResponseHead {
+            version,
+            status,
+            reason,
+            headers,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+        self.headers.write_to(&mut buffer);
+        buffer.into_bytes()
+    }
+}
+
+// Follows the framing of a chunked body as it passes through, to tell where
+// the body ends without decoding it.
+#[derive(Default)]
+pub struct ChunkedFraming {
+    state: ChunkState,
+    line: Vec<u8>,
+}
+
+#[derive(Default)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+impl ChunkedFraming {
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ChunkState::Done)
+    }
+
+    // Takes the next bytes of the body and returns how many of them belong
+    // to it, which is fewer than given only when the body ends among them.
+    pub fn feed(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut position = 0;
+        while position < bytes.len() {
+            match &mut self.state {
+                ChunkState::Data(remaining) => {
+                    let available = (bytes.len() - position) as u64;
+                    let taken = (*remaining).min(available);
+                    *remaining -= taken;
+                    position += taken as usize;
+                    if *remaining == 0 {
+                        self.state = ChunkState::DataEnd;
+                    }
+                }
+                ChunkState::Done => break,
+                _ => {
+                    let byte = bytes[position];
+                    position += 1;
+                    if byte != b'\n' {
+                        if self.line.len() == MAX_CHUNK_LINE_BYTES {
+                            return Err(invalid_chunk("Chunk line too long"));
+                        }
+                        self.line.push(byte);
+                        continue;
+                    }
+                    let line = std::mem::take(&mut self.line);
+                    let line = String::from_utf8_lossy(&line);
+                    let line = line.trim_end_matches('\r');
+                    self.state = match self.state {
+                        ChunkState::Size => {
+                            let size = line.split(';').next().unwrap_or_default().trim();
+                            match u64::from_str_radix(size, 16) {
+                                Ok(0) => ChunkState::Trailer,
+                                Ok(size) => ChunkState::Data(size),
+                                Err(_) => return Err(invalid_chunk("Invalid chunk size")),
+                            }
+                        }
+                        ChunkState::DataEnd => ChunkState::Size,
+                        _ if line.is_empty() => ChunkState::Done,
+                        _ => ChunkState::Trailer,
+                    };
+                }
+            }
+        }
+        Ok(position)
+    }
+}
+
+fn invalid_chunk(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    let bytes_read = reader.read_line(&mut line).await?;
+    if bytes_read == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "EOF reached before completing read",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
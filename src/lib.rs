@@ -1,9 +1,27 @@
 pub mod balance;
 pub mod cache;
+pub mod cache_policy;
+pub mod cache_purge;
+pub mod cache_rules;
+pub mod circuit_breaker;
+pub mod compression;
 pub mod config;
 pub mod config_manager;
+pub mod disk_cache;
 pub mod health_checker;
+pub mod health_events;
+pub mod hedging;
+pub mod http;
+pub mod lru;
+pub mod metrics;
+pub mod outlier_detection;
 pub mod rate_limiter;
+pub mod request_retry;
+pub mod resource_monitor;
 pub mod retry;
+pub mod retry_budget;
 pub mod retry_strategy;
+pub mod session_affinity;
+pub mod single_flight;
 pub mod tcp_connection_manager;
+pub mod timeouts;
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+// A map that keeps its entries in least-recently-used order, with constant
+// time lookups, inserts, removals and evictions. Entries live in a slab and
+// are linked through their indices; the head is the most recently used.
+pub struct Lru<V> {
+    nodes: Vec<Option<Node<V>>>,
+    free: Vec<usize>,
+    index: HashMap<String, usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    bytes: usize,
+}
+
+struct Node<V> {
+    key: String,
+    value: V,
+    size: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+impl<V> Default for Lru<V> {
+    fn default() -> Self {
+        Lru {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+            head: None,
+            tail: None,
+            bytes: 0,
+        }
+    }
+}
+
+impl<V> Lru<V> {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // Total size of the stored entries, as given when they were inserted.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Looks up an entry and marks it as the most recently used.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let slot = *self.index.get(key)?;
+        self.unlink(slot);
+        self.push_front(slot);
+        self.nodes[slot].as_mut().map(|node| &mut node.value)
+    }
+
+    // Inserts or replaces an entry as the most recently used, returning the
+    // value it replaced.
+    pub fn insert(&mut self, key: String, value: V, size: usize) -> Option<V> {
+        let replaced = self.remove(&key);
+        let node = Node {
+            key: key.clone(),
+            value,
+            size,
+            prev: None,
+            next: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.index.insert(key, slot);
+        self.bytes += size;
+        self.push_front(slot);
+        replaced
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let slot = self.index.remove(key)?;
+        self.unlink(slot);
+        let node = self.nodes[slot].take()?;
+        self.free.push(slot);
+        self.bytes -= node.size;
+        Some(node.value)
+    }
+
+    // The least recently used entry, without marking it as used.
+    pub fn peek_lru(&self) -> Option<(&str, &V)> {
+        let node = self.nodes[self.tail?].as_ref()?;
+        Some((node.key.as_str(), &node.value))
+    }
+
+    pub fn pop_lru(&mut self) -> Option<(String, V)> {
+        let key = self.peek_lru()?.0.to_string();
+        let value = self.remove(&key)?;
+        Some((key, value))
+    }
+
+    // Entries from least to most recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        let mut slot = self.tail;
+        std::iter::from_fn(move || {
+            let node = self.nodes[slot?].as_ref()?;
+            slot = node.prev;
+            Some((node.key.as_str(), &node.value))
+        })
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = match &self.nodes[slot] {
+            Some(node) => (node.prev, node.next),
+            None => return,
+        };
+        match prev {
+            Some(prev) => self.node_mut(prev).next = next,
+            None if self.head == Some(slot) => self.head = next,
+            None => {}
+        }
+        match next {
+            Some(next) => self.node_mut(next).prev = prev,
+            None if self.tail == Some(slot) => self.tail = prev,
+            None => {}
+        }
+        let node = self.node_mut(slot);
+        node.prev = None;
+        node.next = None;
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        let old_head = self.head;
+        {
+            let node = self.node_mut(slot);
+            node.prev = None;
+            node.next = old_head;
+        }
+        match old_head {
+            Some(old_head) => self.node_mut(old_head).prev = Some(slot),
+            None => self.tail = Some(slot),
+        }
+        self.head = Some(slot);
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node<V> {
+        self.nodes[slot].as_mut().expect("linked slot holds a node")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lru;
+
+    fn keys<V>(lru: &Lru<V>) -> Vec<&str> {
+        lru.iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut lru = Lru::default();
+        lru.insert("a".to_string(), 1, 1);
+        lru.insert("b".to_string(), 2, 1);
+        lru.insert("c".to_string(), 3, 1);
+        assert_eq!(keys(&lru), ["a", "b", "c"]);
+
+        // Reading an entry makes it the most recently used.
+        assert_eq!(lru.get_mut("a"), Some(&mut 1));
+        assert_eq!(keys(&lru), ["b", "c", "a"]);
+
+        // Replacing an entry does too.
+        assert_eq!(lru.insert("b".to_string(), 20, 1), Some(2));
+        assert_eq!(keys(&lru), ["c", "a", "b"]);
+
+        assert_eq!(lru.peek_lru(), Some(("c", &3)));
+        assert_eq!(lru.pop_lru(), Some(("c".to_string(), 3)));
+        assert_eq!(lru.pop_lru(), Some(("a".to_string(), 1)));
+        assert_eq!(lru.pop_lru(), Some(("b".to_string(), 20)));
+        assert_eq!(lru.pop_lru(), None);
+        assert!(lru.is_empty());
+    }
+
+    #[test]
+    fn reuses_freed_slots_in_order() {
+        let mut lru = Lru::default();
+        lru.insert("a".to_string(), 1, 1);
+        lru.insert("b".to_string(), 2, 1);
+        lru.insert("c".to_string(), 3, 1);
+        assert_eq!(lru.remove("b"), Some(2));
+        lru.insert("d".to_string(), 4, 1);
+        assert_eq!(keys(&lru), ["a", "c", "d"]);
+        assert_eq!(lru.remove("missing"), None);
+        assert_eq!(lru.len(), 3);
+    }
+
+    #[test]
+    fn tracks_bytes_of_stored_entries() {
+        let mut lru = Lru::default();
+        assert_eq!(lru.bytes(), 0);
+        lru.insert("a".to_string(), (), 100);
+        lru.insert("b".to_string(), (), 50);
+        assert_eq!(lru.bytes(), 150);
+
+        // A replaced entry's size is given back before the new one is added.
+        lru.insert("a".to_string(), (), 10);
+        assert_eq!(lru.bytes(), 60);
+
+        lru.remove("b");
+        assert_eq!(lru.bytes(), 10);
+        lru.pop_lru();
+        assert_eq!(lru.bytes(), 0);
+    }
+}
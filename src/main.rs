@@ -1,22 +1,36 @@
-mod balance;
-mod config;
 mod proxy;
-use crate::balance::BalanceStrategy;
-use env_logger;
+use crate::proxy::RouteContext;
 use mobc::Pool;
-use road47::cache::Cache;
+use road47::balance::{BalanceStrategy, BalancerState, SlowStart};
+use road47::cache::{Cache, CacheLimits, DEFAULT_SURROGATE_KEY_HEADER};
+use road47::cache_policy::CachePolicy;
+use road47::cache_purge::PurgeEndpoint;
+use road47::cache_rules::{CacheRule, CacheRules};
+use road47::circuit_breaker::CircuitBreaker;
+use road47::compression::CompressionPolicy;
 use road47::config::RequestModificationRule;
 use road47::config_manager::ConfigManager;
-use road47::health_checker::HealthChecker;
-use road47::rate_limiter::create_rate_limiter;
+use road47::disk_cache::DiskCache;
+use road47::health_checker::{HealthCheckPolicy, HealthChecker, HealthTracker, ProbeResult};
+use road47::health_events::HealthEventBus;
+use road47::hedging::HedgePolicy;
+use road47::metrics::Metrics;
+use road47::outlier_detection::OutlierDetector;
+use road47::rate_limiter::{create_rate_limiter, RateLimitPolicies, RateLimitPolicy};
+use road47::request_retry::RequestRetryPolicy;
+use road47::resource_monitor::ResourceMonitor;
+use road47::retry_budget::RetryBudget;
+use road47::session_affinity::SessionAffinity;
+use road47::single_flight::SingleFlight;
 use road47::tcp_connection_manager::TcpConnectionManager;
+use road47::timeouts::RouteTimeouts;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
-use tokio::time::interval;
+use tokio::time::sleep;
 use tracing::{error, info};
 
 #[tokio::main]
@@ -30,10 +44,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         read_guard.get_config().await
     };
 
-    let rate_limiter = Arc::new(create_rate_limiter(config.rate_limiting));
+    // The top-level rate limit is counted across all routes.
+    let global_rate_limit = config.rate_limiting.clone().map(|rate_limiting| {
+        let limiter = Arc::new(create_rate_limiter(Some(rate_limiting.clone())));
+        (rate_limiting, limiter)
+    });
 
     let health_checker = Arc::new(HealthChecker::new());
     let health_statuses = Arc::new(Mutex::new(HashMap::<String, bool>::new()));
+    let metrics = Arc::new(Metrics::new());
+    let health_events = Arc::new(HealthEventBus::new(config.health_events.as_ref()));
 
     let config_manager_clone: Arc<RwLock<ConfigManager>> = Arc::clone(&config_manager);
 
@@ -44,30 +64,90 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     for route in config.route {
         let timeout = Duration::from_secs(route.timeout_seconds);
-        let manager = TcpConnectionManager::initialize_with(
-            route.target_addrs.clone(),
-            Arc::clone(&config_manager),
-        );
-        let pool = Arc::new(Pool::builder().build(manager));
+        let retry_budget = route
+            .retry_budget
+            .as_ref()
+            .map(|config| Arc::new(RetryBudget::new(config, Arc::clone(&metrics))));
+        let pools = route
+            .target_addrs
+            .iter()
+            .map(|addr| {
+                let manager = TcpConnectionManager::initialize_with(
+                    vec![addr.clone()],
+                    Arc::clone(&config_manager),
+                    retry_budget.clone(),
+                    route.retry_strategy.clone(),
+                );
+                (addr.clone(), Arc::new(Pool::builder().build(manager)))
+            })
+            .collect();
 
         let listener = TcpListener::bind(&route.listen_addr).await?;
         info!("Listening on: {}", route.listen_addr);
 
-        let target_addrs = Arc::new(Mutex::new(VecDeque::from(route.target_addrs)));
+        let target_addrs = Arc::new(Mutex::new(VecDeque::from(route.target_addrs.clone())));
         let balance_strategy = BalanceStrategy::from_str(&route.balance_strategy);
-        let connection_counts = Arc::new(Mutex::new(HashMap::new()));
+        let balancer_state = Arc::new(BalancerState::new(
+            route.slow_start.as_ref().map(SlowStart::new),
+        ));
+        let connection_counts = Arc::new(std::sync::Mutex::new(HashMap::new()));
         let request_limits = Arc::new(Mutex::new(HashMap::new()));
         let max_requests_per_target = route.max_requests_per_target;
-        let resource_endpoints = route
-            .resource_endpoints
-            .as_ref()
-            .map(|endpoints| Arc::new(Mutex::new(endpoints.clone())));
+        let resource_monitor = route.resource_endpoints.as_ref().map(|endpoints| {
+            Arc::new(ResourceMonitor::new(
+                &route.target_addrs,
+                endpoints,
+                route.resource_check.as_ref(),
+            ))
+        });
+        if let Some(monitor) = &resource_monitor {
+            tokio::spawn(Arc::clone(monitor).run());
+        }
 
-        let cache = Arc::new(Mutex::new(Cache::new(
+        let mut cache = Cache::new(CacheLimits {
+            max_entries: route.cache_capacity.unwrap_or_default(),
+            max_bytes: route.cache_max_bytes.unwrap_or_default(),
+            max_entry_bytes: route.cache_max_entry_bytes.unwrap_or_default(),
+            shards: route.cache_shards.unwrap_or_default(),
+        });
+        if let Some(disk_config) = &route.cache_disk {
+            let disk_cache = Arc::new(DiskCache::open(disk_config)?);
+            tokio::spawn(Arc::clone(&disk_cache).run());
+            cache = cache.with_disk_tier(disk_cache);
+        }
+        let surrogate_key_header = route
+            .cache_surrogate_key_header
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SURROGATE_KEY_HEADER.to_string());
+        let cache_purge = route
+            .cache_purge_networks
+            .as_ref()
+            .map(|networks| PurgeEndpoint::new(networks, &surrogate_key_header));
+        let cache = Arc::new(cache.with_surrogate_key_header(surrogate_key_header));
+        // Endpoints listed in cache_enabled_endpoints are cached as exact paths,
+        // after the configured rules.
+        let cache_rules = route
+            .cache_rules
+            .iter()
+            .flatten()
+            .filter_map(CacheRule::new)
+            .chain(
+                route
+                    .cache_enabled_endpoints
+                    .iter()
+                    .flatten()
+                    .map(|path| CacheRule::exact_path(path)),
+            )
+            .collect();
+        let cache_policy = CachePolicy::new(
+            CacheRules::new(cache_rules),
             route.cache_ttl_seconds.unwrap_or_default(),
-            route.cache_capacity.unwrap_or_default(),
-        )));
-        let cache_enabled_endpoints = route.cache_enabled_endpoints.clone();
+            route.cache_ttl_override.unwrap_or(false),
+            route
+                .cache_stale_while_revalidate_seconds
+                .unwrap_or_default(),
+            route.cache_stale_if_error_seconds.unwrap_or_default(),
+        );
         let target_weights = route.target_weights.clone();
 
         let request_modification_rules: Vec<RequestModificationRule> = route
@@ -79,44 +159,119 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let health_check_endpoints_arc = Arc::new(health_check_endpoints.clone());
             let health_checker_clone = Arc::clone(&health_checker);
             let health_statuses_clone = Arc::clone(&health_statuses);
+            let health_events_clone = Arc::clone(&health_events);
+            let health_check_policy = HealthCheckPolicy::new(route.health_check.as_ref());
             tokio::spawn(async move {
-                let mut tick_interval = interval(Duration::from_secs(30));
+                let mut health_tracker = HealthTracker::new();
                 loop {
-                    tick_interval.tick().await;
                     let mut statuses = health_checker_clone
-                        .check_health(&health_check_endpoints_arc)
+                        .check_health(&health_check_endpoints_arc, &health_check_policy)
                         .await;
                     if statuses.is_empty() {
                         error!("Health check failed. Considering all services as down/up based on your policy.");
                         statuses = health_check_endpoints_arc
-                            .iter()
-                            .map(|(key, _)| (key.clone(), true))
-                            .collect::<HashMap<String, bool>>();
+                            .keys()
+                            .map(|key| {
+                                let result = ProbeResult {
+                                    healthy: true,
+                                    reason: "no health check results".to_string(),
+                                    latency: Duration::ZERO,
+                                };
+                                (key.clone(), result)
+                            })
+                            .collect::<HashMap<String, ProbeResult>>();
                     }
 
-                    let mut health = health_statuses_clone.lock().await;
-                    *health = statuses;
+                    let (statuses, events) = health_tracker.apply(statuses, &health_check_policy);
+                    for event in events {
+                        health_events_clone.publish(event).await;
+                    }
+                    health_events_clone.release_settled().await;
+
+                    {
+                        // The status map is shared by all routes, so only this route's
+                        // targets are replaced.
+                        let mut health = health_statuses_clone.lock().await;
+                        health.extend(statuses);
+                    }
+                    sleep(health_check_policy.next_delay()).await;
                 }
             });
         }
 
-        tokio::spawn(proxy::accept_connections(
-            listener,
-            pool,
+        let outlier_detector = route
+            .outlier_detection
+            .as_ref()
+            .map(|config| Arc::new(OutlierDetector::new(config, route.target_addrs.len())));
+        let circuit_breaker = route
+            .circuit_breaker
+            .as_ref()
+            .map(|config| Arc::new(CircuitBreaker::new(config, Arc::clone(&metrics))));
+        let session_affinity = route.session_affinity.as_ref().map(SessionAffinity::new);
+        let request_retry = route.request_retry.as_ref().map(|retry| {
+            let backoff = match &route.retry_strategy {
+                Some(retry_override) => retry_override.apply_to(&config.retry_strategy),
+                None => config.retry_strategy.clone(),
+            };
+            RequestRetryPolicy::new(retry, &backoff)
+        });
+        let hedging = route
+            .hedging
+            .as_ref()
+            .map(|config| HedgePolicy::new(config, Arc::clone(&metrics)));
+
+        let global_policy = global_rate_limit.iter().map(|(rate_limiting, limiter)| {
+            let name = rate_limiting
+                .name
+                .clone()
+                .unwrap_or_else(|| "global".to_string());
+            RateLimitPolicy::shared(name, Arc::clone(limiter), rate_limiting, &route.listen_addr)
+        });
+        let route_policies =
+            route
+                .rate_limits
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(|(index, rate_limiting)| {
+                    let name = rate_limiting
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("{}#{}", route.listen_addr, index + 1));
+                    RateLimitPolicy::new(name, rate_limiting, &route.listen_addr)
+                });
+        let rate_limits = RateLimitPolicies::new(global_policy.chain(route_policies).collect());
+
+        let route_context = Arc::new(RouteContext {
+            pools,
             target_addrs,
-            timeout,
+            timeouts: RouteTimeouts::new(route.timeouts.as_ref(), timeout),
             balance_strategy,
+            balancer_state,
             connection_counts,
             request_limits,
             max_requests_per_target,
-            resource_endpoints,
+            resource_monitor,
             cache,
-            cache_enabled_endpoints,
+            cache_policy,
+            cache_fetches: Arc::new(SingleFlight::default()),
+            cache_purge,
+            compression: route.compression.as_ref().map(CompressionPolicy::new),
             target_weights,
-            Some(health_statuses.clone()),
-            Some(rate_limiter.clone()),
-            Some(request_modification_rules),
-        ));
+            health_statuses: Some(health_statuses.clone()),
+            outlier_detector,
+            circuit_breaker,
+            metrics: Arc::clone(&metrics),
+            metrics_path: route.metrics_path.clone(),
+            rate_limits,
+            rules: request_modification_rules,
+            session_affinity,
+            request_retry,
+            retry_budget,
+            hedging,
+        });
+
+        tokio::spawn(proxy::accept_connections(listener, route_context));
     }
 
     loop {
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+type SeriesKey = (String, Vec<(String, String)>);
+
+// A minimal registry of counters and gauges rendered in the Prometheus text
+// exposition format.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<SeriesKey, u64>>,
+    gauges: Mutex<BTreeMap<SeriesKey, f64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(&self, name: &str, labels: &[(&str, &str)]) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(series_key(name, labels)).or_insert(0) += 1;
+    }
+
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.insert(series_key(name, labels), value);
+    }
+
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let counters = self.counters.lock().unwrap();
+        counters
+            .get(&series_key(name, labels))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let counters = self.counters.lock().unwrap();
+        render_series(&mut output, "counter", counters.iter());
+        let gauges = self.gauges.lock().unwrap();
+        render_series(&mut output, "gauge", gauges.iter());
+        output
+    }
+}
+
+fn series_key(name: &str, labels: &[(&str, &str)]) -> SeriesKey {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    (name.to_string(), labels)
+}
+
+fn render_series<'a, V: std::fmt::Display + 'a>(
+    output: &mut String,
+    kind: &str,
+    series: impl Iterator<Item = (&'a SeriesKey, &'a V)>,
+) {
+    let mut last_name = None;
+    for ((name, labels), value) in series {
+        if last_name != Some(name) {
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            last_name = Some(name);
+        }
+        output.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('"', "\\\"")))
+                .collect();
+            let _ = write!(output, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(output, " {}", value);
+    }
+}
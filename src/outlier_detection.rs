@@ -0,0 +1,132 @@
+use crate::config::OutlierDetectionConfig;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DEFAULT_CONSECUTIVE_FAILURES: u32 = 5;
+const DEFAULT_BASE_EJECTION_SECONDS: u64 = 30;
+const DEFAULT_MAX_EJECTION_SECONDS: u64 = 300;
+const DEFAULT_MAX_EJECTION_PERCENT: f64 = 50.0;
+
+#[derive(Clone, Copy, Debug)]
+pub enum FailureKind {
+    ConnectFailure,
+    Timeout,
+    ServerError(u16),
+}
+
+#[derive(Default)]
+struct TargetState {
+    consecutive_failures: u32,
+    ejection_count: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl TargetState {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+}
+
+// Ejects targets that keep failing live traffic, independently of the active
+// health checks, for a period that doubles with every repeated ejection.
+pub struct OutlierDetector {
+    consecutive_failures: u32,
+    base_ejection: Duration,
+    max_ejection: Duration,
+    max_ejection_percent: f64,
+    target_count: usize,
+    targets: Mutex<HashMap<String, TargetState>>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: &OutlierDetectionConfig, target_count: usize) -> Self {
+        OutlierDetector {
+            consecutive_failures: config
+                .consecutive_failures
+                .unwrap_or(DEFAULT_CONSECUTIVE_FAILURES),
+            base_ejection: Duration::from_secs(
+                config
+                    .base_ejection_seconds
+                    .unwrap_or(DEFAULT_BASE_EJECTION_SECONDS),
+            ),
+            max_ejection: Duration::from_secs(
+                config
+                    .max_ejection_seconds
+                    .unwrap_or(DEFAULT_MAX_EJECTION_SECONDS),
+            ),
+            max_ejection_percent: config
+                .max_ejection_percent
+                .unwrap_or(DEFAULT_MAX_EJECTION_PERCENT),
+            target_count,
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn retain_available(&self, addrs: VecDeque<String>) -> VecDeque<String> {
+        let now = Instant::now();
+        let targets = self.targets.lock().await;
+        addrs
+            .into_iter()
+            .filter(|addr| !targets.get(addr).is_some_and(|state| state.is_ejected(now)))
+            .collect()
+    }
+
+    pub async fn record_success(&self, addr: &str) {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().await;
+        if let Some(state) = targets.get_mut(addr) {
+            state.consecutive_failures = 0;
+            // Forget earlier ejections once the target has stayed healthy for
+            // the longest ejection period.
+            if state
+                .ejected_until
+                .is_some_and(|until| now.duration_since(until) >= self.max_ejection)
+            {
+                state.ejection_count = 0;
+                state.ejected_until = None;
+            }
+        }
+    }
+
+    pub async fn record_failure(&self, addr: &str, kind: FailureKind) {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().await;
+        let ejected_count = targets
+            .iter()
+            .filter(|(other, state)| other.as_str() != addr && state.is_ejected(now))
+            .count();
+
+        let state = targets.entry(addr.to_string()).or_default();
+        if state.is_ejected(now) {
+            return;
+        }
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < self.consecutive_failures {
+            return;
+        }
+
+        let ejected_percent = (ejected_count + 1) as f64 * 100.0 / self.target_count.max(1) as f64;
+        if ejected_percent > self.max_ejection_percent {
+            warn!(
+                "Not ejecting {} after {:?}: {:.0}% of targets would be ejected",
+                addr, kind, ejected_percent
+            );
+            return;
+        }
+
+        let multiplier = 2_u32.saturating_pow(state.ejection_count);
+        let ejection = std::cmp::min(
+            self.base_ejection.saturating_mul(multiplier),
+            self.max_ejection,
+        );
+        state.ejection_count += 1;
+        state.consecutive_failures = 0;
+        state.ejected_until = Some(now + ejection);
+        info!(
+            "Ejecting {} for {:?} after consecutive failures (last: {:?})",
+            addr, ejection, kind
+        );
+    }
+}
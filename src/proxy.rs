@@ -1,335 +1,1476 @@
-use crate::balance::BalanceStrategy;
 use mobc::Error as MobcError;
 use mobc::Pool;
-use road47::cache::Cache;
+use road47::balance::{BalanceStrategy, BalancerState};
+use road47::cache::{Cache, CacheHit, CacheLookup, CachedResponse, Purge};
+use road47::cache_policy::{
+    add_validators, merge_not_modified, not_modified, not_modified_response, CachePolicy,
+};
+use road47::cache_purge::PurgeEndpoint;
+use road47::circuit_breaker::{CircuitBreaker, CircuitPermit};
+use road47::compression::{CompressionPolicy, Negotiated};
 use road47::config::RequestModificationRule;
-use road47::rate_limiter::RateLimiter;
+use road47::hedging::HedgePolicy;
+use road47::http::{ChunkedFraming, RequestHead, ResponseHead};
+use road47::metrics::Metrics;
+use road47::outlier_detection::{FailureKind, OutlierDetector};
+use road47::rate_limiter::RateLimitPolicies;
+use road47::request_retry::{RequestRetryPolicy, RetryCondition};
+use road47::resource_monitor::ResourceMonitor;
+use road47::retry::race_hedged;
+use road47::retry_budget::RetryBudget;
+use road47::retry_strategy::RetryContext;
+use road47::session_affinity::SessionAffinity;
+use road47::single_flight::{self, Flight, FlightGuard, SingleFlight};
 use road47::tcp_connection_manager::TcpConnectionManager;
+use road47::timeouts::{PhaseTimeout, RequestDeadline, RouteTimeouts, TimeoutPhase};
 use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::io::{self, AsyncBufReadExt, BufReader};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 use tracing::{info, warn};
 
-pub async fn accept_connections(
-    listener: TcpListener,
-    pool: Arc<Pool<TcpConnectionManager>>,
-    target_addrs: Arc<Mutex<VecDeque<String>>>,
-    timeout: Duration,
-    balance_strategy: BalanceStrategy,
-    connection_counts: Arc<Mutex<HashMap<String, usize>>>,
-    request_limits: Arc<Mutex<HashMap<String, usize>>>,
-    max_requests_per_target: Option<usize>,
-    resource_endpoints: Option<Arc<Mutex<Vec<String>>>>,
-    cache: Arc<Mutex<Cache>>,
-    cache_enabled_endpoints: Option<Vec<String>>,
-    target_weights: Option<HashMap<String, usize>>,
-    health_statuses: Option<Arc<Mutex<HashMap<String, bool>>>>,
-    rate_limiter: Option<Arc<Box<dyn RateLimiter + Send + Sync>>>,
-    rules: Option<Vec<RequestModificationRule>>,
-) -> io::Result<()> {
-    while let Ok((mut incoming, addr)) = listener.accept().await {
+pub struct RouteContext {
+    pub pools: HashMap<String, Arc<Pool<TcpConnectionManager>>>,
+    pub target_addrs: Arc<Mutex<VecDeque<String>>>,
+    pub timeouts: RouteTimeouts,
+    pub balance_strategy: BalanceStrategy,
+    pub balancer_state: Arc<BalancerState>,
+    pub connection_counts: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+    pub request_limits: Arc<Mutex<HashMap<String, usize>>>,
+    pub max_requests_per_target: Option<usize>,
+    pub resource_monitor: Option<Arc<ResourceMonitor>>,
+    pub cache: Arc<Cache>,
+    pub cache_policy: CachePolicy,
+    pub cache_fetches: Arc<SingleFlight>,
+    pub cache_purge: Option<PurgeEndpoint>,
+    pub compression: Option<CompressionPolicy>,
+    pub target_weights: Option<HashMap<String, usize>>,
+    pub health_statuses: Option<Arc<Mutex<HashMap<String, bool>>>>,
+    pub outlier_detector: Option<Arc<OutlierDetector>>,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub metrics: Arc<Metrics>,
+    pub metrics_path: Option<String>,
+    pub rate_limits: RateLimitPolicies,
+    pub rules: Vec<RequestModificationRule>,
+    pub session_affinity: Option<SessionAffinity>,
+    pub request_retry: Option<RequestRetryPolicy>,
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    pub hedging: Option<HedgePolicy>,
+}
+
+// Carried inside errors writing the response to the client, so that a client
+// going away is not held against the target.
+#[derive(Debug)]
+struct ClientWriteError(io::Error);
+
+impl fmt::Display for ClientWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "writing to client failed: {}", self.0)
+    }
+}
+
+impl Error for ClientWriteError {}
+
+impl ClientWriteError {
+    fn wrap(error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), ClientWriteError(error))
+    }
+
+    fn from_error(error: &io::Error) -> Option<&io::Error> {
+        let inner = error.get_ref()?.downcast_ref::<ClientWriteError>()?;
+        Some(&inner.0)
+    }
+}
+
+// A response whose head has been read from the target but whose body is
+// still to be relayed. The write half is held so the connection stays open.
+struct UpstreamResponse {
+    reader: BufReader<OwnedReadHalf>,
+    _writer: OwnedWriteHalf,
+    head: ResponseHead,
+}
+
+pub async fn accept_connections(listener: TcpListener, route: Arc<RouteContext>) -> io::Result<()> {
+    while let Ok((incoming, addr)) = listener.accept().await {
         let client_ip = addr.ip().to_string();
-        if let Some(limiter) = &rate_limiter {
-            if !limiter.allow(&client_ip) {
-                warn!("Rate limit exceeded for IP: {}", client_ip);
-                let response = "HTTP/1.1 429 Too Many Requests\r\nContent-Type: text/plain\r\nContent-Length: 33\r\n\r\nError: Rate limit exceeded.\n";
-                if let Err(e) = incoming.write_all(response.as_bytes()).await {
-                    warn!(
-                        "Failed to send rate limit exceeded response to {}: {}",
-                        client_ip, e
-                    );
+        let route = Arc::clone(&route);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(incoming, client_ip, &route).await {
+                warn!("Error proxying connection: {:?}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_connection(
+    mut incoming: TcpStream,
+    client_ip: String,
+    route: &Arc<RouteContext>,
+) -> io::Result<()> {
+    let (ri, mut wi) = incoming.split();
+    let mut reader = BufReader::new(ri);
+    let deadline = route.timeouts.start();
+    let header_read = deadline.run(
+        TimeoutPhase::ClientHeader,
+        RequestHead::read_from(&mut reader),
+    );
+    let mut request = match header_read.await {
+        Ok(request) => request,
+        Err(e) => return fail_with_timeout(route, &mut wi, e).await,
+    };
+    if route.metrics_path.as_deref() == Some(request.path.as_str()) {
+        return send_response(
+            &mut wi,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            &route.metrics.render(),
+        )
+        .await;
+    }
+    if let Some(policy) = route.rate_limits.check(&request, &client_ip) {
+        warn!(
+            "Rate limit {} exceeded for client {}",
+            policy.name, client_ip
+        );
+        route
+            .metrics
+            .increment("road47_rate_limited_total", &[("policy", &policy.name)]);
+        return send_error_response(
+            &mut wi,
+            "429 Too Many Requests",
+            "Error: Rate limit exceeded.\n",
+        )
+        .await;
+    }
+    if let Some(endpoint) = &route.cache_purge {
+        if PurgeEndpoint::is_purge(&request) {
+            return purge_cache(&mut wi, &request, &client_ip, endpoint, route).await;
+        }
+    }
+    apply_request_modification(&mut request, &route.rules);
+
+    // Held while this request fetches an entry other requests are waiting for.
+    let mut _cache_fill = None;
+    if route.cache_policy.is_cacheable(&request)
+        && route.cache_policy.allows_cached_response(&request)
+    {
+        if let Some(key) = route.cache_policy.cache_key(&request) {
+            let mut lookup = lookup_cache(route, &key, &request).await;
+            let needs_fetch = match &lookup {
+                CacheLookup::Fresh(_) => false,
+                CacheLookup::Stale(stale) => !stale.stale_while_revalidate,
+                CacheLookup::Miss => true,
+            };
+            if needs_fetch {
+                match route.cache_fetches.join(&key) {
+                    Flight::Leader(flight) => _cache_fill = Some(flight),
+                    Flight::Follower(done) => {
+                        route.metrics.increment("road47_cache_coalesced_total", &[]);
+                        // A leader that takes too long is not waited for.
+                        let wait = async {
+                            single_flight::wait(done).await;
+                            Ok(())
+                        };
+                        let _ = deadline.run(TimeoutPhase::UpstreamFirstByte, wait).await;
+                        lookup = lookup_cache(route, &key, &request).await;
+                    }
                 }
-                continue;
+            }
+            match lookup {
+                CacheLookup::Fresh(hit) => {
+                    return serve_from_cache(&mut wi, &request, &key, hit, route, &deadline).await;
+                }
+                CacheLookup::Stale(stale) if stale.stale_while_revalidate => {
+                    if let Flight::Leader(flight) = route.cache_fetches.join(&key) {
+                        tokio::spawn(refresh_in_background(
+                            Arc::clone(route),
+                            request.clone(),
+                            key.clone(),
+                            stale.response.clone(),
+                            client_ip,
+                            flight,
+                        ));
+                    }
+                    record_stale_served(route, "revalidating");
+                    return serve_from_cache(&mut wi, &request, &key, stale, route, &deadline)
+                        .await;
+                }
+                CacheLookup::Stale(stale) if !has_body(&request) => {
+                    let revalidation =
+                        revalidate(&mut wi, &request, key, stale, &client_ip, route, &deadline);
+                    if revalidation.await? {
+                        return Ok(());
+                    }
+                }
+                _ => {}
             }
         }
-        let target_addrs_clone = Arc::clone(&target_addrs);
-        let timeout_clone = timeout;
-        let connection_counts_clone = Arc::clone(&connection_counts);
-        let request_limits_clone = Arc::clone(&request_limits);
-        let resource_endpoints_clone = resource_endpoints.as_ref().map(Arc::clone);
-        let pool_clone = Arc::clone(&pool);
-        let cache_clone = Arc::clone(&cache);
-        let cache_enabled_endpoints_clone = cache_enabled_endpoints.clone();
-        let target_weights_clone = target_weights.clone();
-        let health_statuses_clone = health_statuses.as_ref().map(Arc::clone);
-        let rules_for_connection = rules.clone();
-        tokio::spawn(async move {
-            let connection_counts_clone_for_proxy = Arc::clone(&connection_counts_clone);
-            let client_ip_for_strategy = match balance_strategy {
-                BalanceStrategy::IPHash => Some(client_ip.clone()),
-                _ => None,
+    }
+
+    let replay_limit = [
+        route
+            .request_retry
+            .as_ref()
+            .filter(|policy| policy.allows_method(&request.method))
+            .map(|policy| policy.max_body_bytes),
+        route
+            .hedging
+            .as_ref()
+            .filter(|hedging| hedging.allows_method(&request.method))
+            .map(|hedging| hedging.max_body_bytes),
+    ]
+    .into_iter()
+    .flatten()
+    .max();
+    if let Some(max_bytes) = replay_limit {
+        let body_read = deadline.run(
+            TimeoutPhase::ClientBody,
+            read_replayable_body(&mut reader, &request, max_bytes),
+        );
+        match body_read.await {
+            Ok(Some(body)) => {
+                return proxy_buffered(wi, request, body, client_ip, route, &deadline).await
+            }
+            Ok(None) => {}
+            Err(e) => return fail_with_timeout(route, &mut wi, e).await,
+        }
+    }
+
+    let Some((target_addr, affinity_cookie)) = choose_target(route, &request, client_ip, &[]).await
+    else {
+        warn!("No target addresses available or all targets are down.");
+        return send_error_response(
+            &mut wi,
+            "503 Service Unavailable",
+            "Error: No healthy targets available.\n",
+        )
+        .await;
+    };
+    let Some(_permit) = try_acquire_circuit(route, &target_addr).await else {
+        warn!("Circuit for {} is not accepting requests", target_addr);
+        return send_error_response(
+            &mut wi,
+            "503 Service Unavailable",
+            "Error: Target circuit is open.\n",
+        )
+        .await;
+    };
+
+    record_request(route, &target_addr).await;
+    let target = match connect_to_target(route, &target_addr, &deadline).await {
+        Ok(target) => target,
+        Err(e) => {
+            let kind = match e.kind() {
+                io::ErrorKind::TimedOut => FailureKind::Timeout,
+                _ => FailureKind::ConnectFailure,
+            };
+            record_outcome(route, &target_addr, Err(kind)).await;
+            return fail_with_timeout(route, &mut wi, e).await;
+        }
+    };
+    proxy_traffic_and_cache_response(
+        reader,
+        wi,
+        target,
+        &target_addr,
+        request,
+        affinity_cookie,
+        route,
+        &deadline,
+    )
+    .await
+}
+
+// Answers a timed out request with 408 or 504 when no response has been
+// started yet, then passes the error on.
+async fn fail_with_timeout<W: AsyncWrite + Unpin>(
+    route: &RouteContext,
+    client_writer: &mut W,
+    error: io::Error,
+) -> io::Result<()> {
+    if let Some(timeout) = record_timeout(route, &error) {
+        if let Some(status) = timeout.phase.status() {
+            let message = format!("Error: {}.\n", timeout);
+            let _ = send_error_response(client_writer, status, &message).await;
+        }
+    }
+    Err(error)
+}
+
+fn record_timeout<'e>(route: &RouteContext, error: &'e io::Error) -> Option<&'e PhaseTimeout> {
+    let error = ClientWriteError::from_error(error).unwrap_or(error);
+    let timeout = PhaseTimeout::from_error(error)?;
+    warn!("Request timed out: {}", timeout);
+    route.metrics.increment(
+        "road47_timeouts_total",
+        &[("phase", timeout.phase.as_str())],
+    );
+    Some(timeout)
+}
+
+async fn purge_cache<W: AsyncWrite + Unpin>(
+    client_writer: &mut W,
+    request: &RequestHead,
+    client_ip: &str,
+    endpoint: &PurgeEndpoint,
+    route: &RouteContext,
+) -> io::Result<()> {
+    if !endpoint.allows(client_ip) {
+        warn!("Rejected cache purge from {}", client_ip);
+        return send_error_response(
+            client_writer,
+            "403 Forbidden",
+            "Error: Purging is not allowed from this address.\n",
+        )
+        .await;
+    }
+    let mut purged = 0;
+    for purge in endpoint.purges(request, &route.cache_policy) {
+        let kind = match &purge {
+            Purge::Key(_) => "key",
+            Purge::Prefix(_) => "prefix",
+            Purge::Tag(_) => "tag",
+        };
+        purged += route.cache.purge(&purge).await;
+        route
+            .metrics
+            .increment("road47_cache_purges_total", &[("kind", kind)]);
+    }
+    info!("Purged {} cached responses for {}", purged, client_ip);
+    send_response(
+        client_writer,
+        "200 OK",
+        "text/plain",
+        &format!("Purged {} cached responses.\n", purged),
+    )
+    .await
+}
+
+async fn lookup_cache(route: &RouteContext, key: &str, request: &RequestHead) -> CacheLookup {
+    route.cache.get(key, &request.headers).await
+}
+
+fn record_stale_served(route: &RouteContext, reason: &str) {
+    route
+        .metrics
+        .increment("road47_cache_stale_served_total", &[("reason", reason)]);
+}
+
+// Asks a target for a fresh copy of a stale cache entry, conditionally when it
+// has validators. A 304 refreshes the entry and the client is answered from
+// the cache; any other response is relayed and replaces it, unless it is a
+// server error and the entry may be served stale on errors. Returns false,
+// with nothing sent to the client, when the target could not be asked.
+async fn revalidate(
+    client_writer: &mut WriteHalf<'_>,
+    request: &RequestHead,
+    key: String,
+    stale: CacheHit,
+    client_ip: &str,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<bool> {
+    let target = choose_target(route, request, client_ip.to_string(), &[]).await;
+    let target = match target {
+        Some((target_addr, affinity_cookie)) => try_acquire_circuit(route, &target_addr)
+            .await
+            .map(|permit| (target_addr, affinity_cookie, permit)),
+        None => None,
+    };
+    let Some((target_addr, affinity_cookie, _permit)) = target else {
+        return serve_stale_on_error(client_writer, request, &key, stale, route, deadline).await;
+    };
+
+    let conditional = revalidation_request(request, &stale.response.head);
+    record_request(route, &target_addr).await;
+    let _connection = track_connection(route, &target_addr);
+    let result = attempt_target(route, &target_addr, &conditional, &[], None, deadline).await;
+    let sent = match result {
+        Ok((_, upstream)) if upstream.head.status == 304 => {
+            info!("Revalidated cached {} with {}", request.path, target_addr);
+            let response =
+                store_revalidated(route, request, key.clone(), stale.response, &upstream.head)
+                    .await;
+            let hit = CacheHit {
+                response,
+                age: Duration::ZERO,
+                stale_while_revalidate: false,
+                stale_if_error: false,
             };
-            if let Some(target_addr) = balance_strategy
-                .select_target(
-                    target_addrs_clone,
-                    connection_counts_clone,
-                    request_limits_clone,
-                    max_requests_per_target,
-                    resource_endpoints_clone,
-                    target_weights_clone,
-                    health_statuses_clone,
-                    client_ip_for_strategy,
+            serve_from_cache(client_writer, request, &key, hit, route, deadline).await?;
+            true
+        }
+        Ok((_, upstream)) if upstream.head.status >= 500 && stale.stale_if_error => {
+            warn!(
+                "Serving stale {} after {} from {}",
+                request.path, upstream.head.status, target_addr
+            );
+            serve_stale_on_error(client_writer, request, &key, stale, route, deadline).await?
+        }
+        Ok((_, mut upstream)) => {
+            let relayed = relay_response(
+                &mut upstream.reader,
+                client_writer,
+                request,
+                upstream.head,
+                affinity_cookie,
+                route,
+                deadline,
+            )
+            .await;
+            match relayed {
+                Ok((response, body)) => store_in_cache(route, request, response, body).await,
+                Err(e) => warn!("Proxy operation failed for {}: {:?}", target_addr, e),
+            }
+            true
+        }
+        Err((_, e)) => {
+            warn!("Revalidation with {} failed: {}", target_addr, e);
+            serve_stale_on_error(client_writer, request, &key, stale, route, deadline).await?
+        }
+    };
+    Ok(sent)
+}
+
+// Answers from a stale entry when it is within its stale-if-error window.
+// Returns false, with nothing sent, otherwise.
+async fn serve_stale_on_error(
+    client_writer: &mut WriteHalf<'_>,
+    request: &RequestHead,
+    key: &str,
+    stale: CacheHit,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<bool> {
+    if !stale.stale_if_error {
+        return Ok(false);
+    }
+    serve_from_cache(client_writer, request, key, stale, route, deadline).await?;
+    Ok(true)
+}
+
+// Refreshes a stale cache entry after the client has been answered from it.
+// The flight is held until the entry is replaced so that other requests keep
+// being answered from the stale entry in the meantime.
+async fn refresh_in_background(
+    route: Arc<RouteContext>,
+    request: RequestHead,
+    key: String,
+    stale: CachedResponse,
+    client_ip: String,
+    _flight: FlightGuard,
+) {
+    let deadline = route.timeouts.start();
+    let Some((target_addr, _)) = choose_target(&route, &request, client_ip, &[]).await else {
+        return;
+    };
+    let Some(_permit) = try_acquire_circuit(&route, &target_addr).await else {
+        return;
+    };
+
+    let conditional = revalidation_request(&request, &stale.head);
+    record_request(&route, &target_addr).await;
+    let _connection = track_connection(&route, &target_addr);
+    let result = attempt_target(&route, &target_addr, &conditional, &[], None, &deadline).await;
+    match result {
+        Ok((_, upstream)) if upstream.head.status == 304 => {
+            info!("Revalidated cached {} with {}", request.path, target_addr);
+            store_revalidated(&route, &request, key, stale, &upstream.head).await;
+        }
+        Ok((_, mut upstream)) => {
+            let mut body = Vec::new();
+            let mut sink = io::sink();
+            let read = read_and_write(&mut upstream.reader, &mut sink, &mut body, &deadline);
+            match read.await {
+                Ok(_) => store_in_cache(&route, &request, upstream.head, body).await,
+                Err(e) => warn!("Background refresh from {} failed: {}", target_addr, e),
+            }
+        }
+        Err((_, e)) => warn!("Background refresh from {} failed: {}", target_addr, e),
+    }
+}
+
+// The request sent to refresh `stored`: the client's request made conditional
+// on the stored validators, with any conditions of the client's own removed.
+fn revalidation_request(request: &RequestHead, stored: &ResponseHead) -> RequestHead {
+    let mut conditional = request.clone();
+    add_validators(&mut conditional, stored);
+    conditional.headers.set("Connection", "close");
+    conditional
+}
+
+// Applies a 304 to the stored response and stores the result again.
+async fn store_revalidated(
+    route: &RouteContext,
+    request: &RequestHead,
+    key: String,
+    stored: CachedResponse,
+    not_modified: &ResponseHead,
+) -> CachedResponse {
+    let response = CachedResponse {
+        head: merge_not_modified(&stored.head, not_modified),
+        body: stored.body,
+    };
+    if let Some(freshness) = route.cache_policy.freshness(request, &response.head) {
+        route
+            .cache
+            .put(key, &request.headers, response.clone(), freshness)
+            .await;
+    }
+    response
+}
+
+fn has_body(request: &RequestHead) -> bool {
+    request.headers.get("Transfer-Encoding").is_some()
+        || request
+            .headers
+            .get("Content-Length")
+            .is_some_and(|length| length.trim() != "0")
+}
+
+// Buffers the request body so the request can be replayed against another
+// target. Returns None, leaving the body unread, when it is chunked or larger
+// than `max_bytes`.
+async fn read_replayable_body<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    request: &RequestHead,
+    max_bytes: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    if request.headers.get("Transfer-Encoding").is_some() {
+        return Ok(None);
+    }
+    let length = match request.headers.get("Content-Length") {
+        Some(value) => value.trim().parse::<usize>().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length header")
+        })?,
+        None => 0,
+    };
+    if length > max_bytes {
+        return Ok(None);
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(body))
+}
+
+// Proxies a request whose body has been buffered, so that it can be retried
+// on another target and, on hedged routes, raced against a second copy.
+async fn proxy_buffered(
+    mut client_writer: WriteHalf<'_>,
+    mut request: RequestHead,
+    body: Vec<u8>,
+    client_ip: String,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<()> {
+    let retry_policy = route
+        .request_retry
+        .as_ref()
+        .filter(|policy| policy.allows_method(&request.method));
+    let hedging = route
+        .hedging
+        .as_ref()
+        .filter(|hedging| hedging.allows_method(&request.method));
+    let strategy = retry_policy.map(|policy| policy.strategy());
+    let per_try_timeout = retry_policy.and_then(|policy| policy.per_try_timeout);
+    request.headers.set("Connection", "close");
+
+    let mut tried: Vec<String> = Vec::new();
+    let mut retries = 0;
+    loop {
+        let chosen = match choose_target(route, &request, client_ip.clone(), &tried).await {
+            Some(chosen) => Some(chosen),
+            // Every available target has been tried, so go around again.
+            None if !tried.is_empty() => {
+                choose_target(route, &request, client_ip.clone(), &[]).await
+            }
+            None => None,
+        };
+        let Some((target_addr, affinity_cookie)) = chosen else {
+            warn!("No target addresses available or all targets are down.");
+            return send_error_response(
+                &mut client_writer,
+                "503 Service Unavailable",
+                "Error: No healthy targets available.\n",
+            )
+            .await;
+        };
+        let Some(_permit) = try_acquire_circuit(route, &target_addr).await else {
+            warn!("Circuit for {} is not accepting requests", target_addr);
+            return send_error_response(
+                &mut client_writer,
+                "503 Service Unavailable",
+                "Error: Target circuit is open.\n",
+            )
+            .await;
+        };
+
+        // Retries and hedges are paid for from the budget the original
+        // request adds to.
+        if retries == 0 {
+            record_request(route, &target_addr).await;
+        }
+        let connection = track_connection(route, &target_addr);
+        let result = match hedging {
+            Some(hedging) => {
+                send_hedged_request(
+                    route,
+                    &target_addr,
+                    &request,
+                    &body,
+                    per_try_timeout,
+                    deadline,
+                    hedging,
+                    &client_ip,
                 )
                 .await
-            {
-                if let Err(e) = proxy_connection(
-                    incoming,
+            }
+            None => {
+                attempt_target(
+                    route,
                     &target_addr,
-                    timeout_clone,
-                    connection_counts_clone_for_proxy,
-                    pool_clone,
-                    cache_clone,
-                    cache_enabled_endpoints_clone,
-                    rules_for_connection,
+                    &request,
+                    &body,
+                    per_try_timeout,
+                    deadline,
                 )
                 .await
-                {
-                    warn!("Error proxying connection to {}: {:?}", target_addr, e);
-                }
-            } else {
-                warn!("No target addresses available or all targets are down.");
             }
+        };
+        let (condition, context) = match &result {
+            Ok((_, upstream)) => (
+                RetryCondition::Status(upstream.head.status),
+                RetryContext::from_response(
+                    upstream.head.status,
+                    upstream.head.headers.get("Retry-After"),
+                ),
+            ),
+            Err((condition, e)) => (*condition, RetryContext::from_error(e)),
+        };
+
+        let retry_delay = match (retry_policy, &strategy) {
+            (Some(policy), Some(strategy))
+                if policy.should_retry(condition)
+                    && strategy.should_retry(retries, policy.max_retries) =>
+            {
+                strategy.delay_with_context(retries, &context)
+            }
+            _ => None,
+        };
+        // A retry that could not start before the request's deadline is not
+        // worth waiting for.
+        let retry_delay = retry_delay.filter(|delay| {
+            deadline
+                .remaining()
+                .is_none_or(|remaining| *delay < remaining)
         });
+        if let Some(delay) = retry_delay {
+            if budget_allows_retry(route, &target_addr).await {
+                drop(connection);
+                warn!(
+                    "Retrying {} {} after {:?} from {}",
+                    request.method, request.path, condition, target_addr
+                );
+                tried.push(target_addr);
+                time::sleep(delay).await;
+                retries += 1;
+                continue;
+            }
+        }
+
+        let proxy_result = match result {
+            Ok((responder, mut upstream)) => {
+                // A winning hedge re-pins the client to the target that answered.
+                let affinity_cookie = match &route.session_affinity {
+                    Some(affinity) if responder != target_addr => {
+                        Some(affinity.set_cookie_header(&responder))
+                    }
+                    _ => affinity_cookie,
+                };
+                relay_response(
+                    &mut upstream.reader,
+                    &mut client_writer,
+                    &request,
+                    upstream.head,
+                    affinity_cookie,
+                    route,
+                    deadline,
+                )
+                .await
+                .map(Some)
+            }
+            Err((condition, e)) => {
+                warn!("Proxy operation failed for {}: {:?}", target_addr, e);
+                record_timeout(route, &e);
+                let status = match condition {
+                    RetryCondition::Timeout => "504 Gateway Timeout",
+                    _ => "502 Bad Gateway",
+                };
+                send_error_response(
+                    &mut client_writer,
+                    status,
+                    "Error: Upstream request failed.\n",
+                )
+                .await
+                .map(|_| None)
+            }
+        };
+        drop(connection);
+
+        match proxy_result {
+            Ok(Some((response, body))) => {
+                store_in_cache(route, &request, response, body).await;
+                info!("Proxy operation completed successfully for {}", target_addr);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Proxy operation failed for {}: {:?}", target_addr, e),
+        }
+        return Ok(());
     }
-    Ok(())
 }
 
-async fn handle_request_modification(
-    incoming: &mut TcpStream,
-    rules: &[RequestModificationRule],
-) -> io::Result<()> {
-    let mut buffer = Vec::new();
-    incoming.read_to_end(&mut buffer).await?;
-    let request_str = String::from_utf8_lossy(&buffer);
-    let headers_end = request_str
-        .find("\r\n\r\n")
-        .unwrap_or_else(|| request_str.len());
-    let (header_str, body) = request_str.split_at(headers_end);
-    let mut lines = header_str.lines();
-    let request_line = lines.next().unwrap_or_default();
-    let request_parts: Vec<&str> = request_line.split_whitespace().collect();
-    if request_parts.len() < 3 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Malformed request line",
-        ));
-    }
-    let (method, mut path) = (request_parts[0], request_parts[1].to_string());
-    let mut headers = HashMap::new();
-    for line in lines {
-        if let Some((key, value)) = line.split_once(": ") {
-            headers.insert(key.to_string(), value.to_string());
+// Sends the request to `target_addr` and, if no response head has arrived
+// after the hedge delay, a copy to another target. Returns the target that
+// answered first along with its response.
+#[allow(clippy::too_many_arguments)]
+async fn send_hedged_request(
+    route: &RouteContext,
+    target_addr: &str,
+    request: &RequestHead,
+    body: &[u8],
+    per_try_timeout: Option<Duration>,
+    deadline: &RequestDeadline<'_>,
+    hedging: &HedgePolicy,
+    client_ip: &str,
+) -> Result<(String, UpstreamResponse), (RetryCondition, io::Error)> {
+    hedging.record_request().await;
+    let started = Instant::now();
+    let primary = Box::pin(attempt_target(
+        route,
+        target_addr,
+        request,
+        body,
+        per_try_timeout,
+        deadline,
+    ));
+    let hedge = Box::pin(async move {
+        let not_sent = |reason: &str| {
+            info!(
+                "Not hedging {} {}: {}",
+                request.method, request.path, reason
+            );
+            None
+        };
+        let excluded = [target_addr.to_string()];
+        let Some((hedge_addr, _)) =
+            choose_target(route, request, client_ip.to_string(), &excluded).await
+        else {
+            return not_sent("no other target available");
+        };
+        if !hedging.try_acquire(target_addr).await {
+            return not_sent("hedge budget exhausted");
+        }
+        let Some(_permit) = try_acquire_circuit(route, &hedge_addr).await else {
+            return not_sent("circuit open");
+        };
+        info!(
+            "Hedging {} {} to {}",
+            request.method, request.path, hedge_addr
+        );
+        Some(attempt_target(route, &hedge_addr, request, body, per_try_timeout, deadline).await)
+    });
+
+    let result = race_hedged(primary, hedge, hedging.delay().await).await;
+    if let Ok((responder, _)) = &result {
+        hedging.record_latency(started.elapsed()).await;
+        if responder != target_addr {
+            hedging.record_hedge_win(target_addr);
+        }
+    }
+    result
+}
+
+// Sends the request to one target and records the outcome for outlier
+// detection and the circuit breaker.
+async fn attempt_target(
+    route: &RouteContext,
+    target_addr: &str,
+    request: &RequestHead,
+    body: &[u8],
+    per_try_timeout: Option<Duration>,
+    deadline: &RequestDeadline<'_>,
+) -> Result<(String, UpstreamResponse), (RetryCondition, io::Error)> {
+    let result =
+        send_buffered_request(route, target_addr, request, body, per_try_timeout, deadline).await;
+    let outcome = match &result {
+        Ok(upstream) if upstream.head.status >= 500 => {
+            Err(FailureKind::ServerError(upstream.head.status))
         }
+        Ok(_) => Ok(()),
+        Err((RetryCondition::Timeout, _)) => Err(FailureKind::Timeout),
+        Err(_) => Err(FailureKind::ConnectFailure),
+    };
+    record_outcome(route, target_addr, outcome).await;
+    result.map(|upstream| (target_addr.to_string(), upstream))
+}
+
+// The permit to send a request to `target_addr`, or None while its circuit is
+// not accepting requests. The permit must be held until the request is done.
+async fn try_acquire_circuit(route: &RouteContext, target_addr: &str) -> Option<CircuitPermit> {
+    match &route.circuit_breaker {
+        Some(circuit_breaker) => circuit_breaker.try_acquire(target_addr).await,
+        None => Some(CircuitPermit::default()),
+    }
+}
+
+async fn record_request(route: &RouteContext, target_addr: &str) {
+    if let Some(budget) = &route.retry_budget {
+        budget.record_request(target_addr).await;
     }
+}
+
+async fn budget_allows_retry(route: &RouteContext, target_addr: &str) -> bool {
+    match &route.retry_budget {
+        Some(budget) => budget.try_withdraw(target_addr).await,
+        None => true,
+    }
+}
+
+// Sends a fully buffered request and reads the response head, classifying any
+// failure so the caller can decide whether to retry elsewhere.
+async fn send_buffered_request(
+    route: &RouteContext,
+    target_addr: &str,
+    request: &RequestHead,
+    body: &[u8],
+    per_try_timeout: Option<Duration>,
+    deadline: &RequestDeadline<'_>,
+) -> Result<UpstreamResponse, (RetryCondition, io::Error)> {
+    let target = connect_to_target(route, target_addr, deadline)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut => (RetryCondition::Timeout, e),
+            _ => (RetryCondition::ConnectFailure, e),
+        })?;
+    let (ro, mut wo) = target.into_split();
+    let mut reader = BufReader::new(ro);
+
+    let exchange = deadline.run(TimeoutPhase::UpstreamFirstByte, async {
+        wo.write_all(&request.to_bytes()).await?;
+        wo.write_all(body).await?;
+        ResponseHead::read_from(&mut reader).await
+    });
+    let head = match per_try_timeout {
+        Some(limit) => time::timeout(limit, exchange).await.unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Upstream response timed out",
+            ))
+        }),
+        None => exchange.await,
+    };
+    match head {
+        Ok(head) => Ok(UpstreamResponse {
+            reader,
+            _writer: wo,
+            head,
+        }),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Err((RetryCondition::Timeout, e)),
+        Err(e) => Err((RetryCondition::Reset, e)),
+    }
+}
+
+// Returns the chosen target and, when a new affinity has been assigned, the
+// `Set-Cookie` value that pins the client to it. Targets in `excluded` are
+// skipped, including a pinned one.
+async fn choose_target(
+    route: &RouteContext,
+    request: &RequestHead,
+    client_ip: String,
+    excluded: &[String],
+) -> Option<(String, Option<String>)> {
+    if let Some(affinity) = &route.session_affinity {
+        let pinned_target = request
+            .cookie(affinity.cookie_name())
+            .and_then(|value| affinity.target_from_cookie(value));
+        if let Some(target) = pinned_target {
+            let available = BalanceStrategy::filter_addresses(
+                &route.target_addrs,
+                route.health_statuses.as_ref(),
+                route.outlier_detector.as_ref(),
+                route.circuit_breaker.as_ref(),
+                excluded,
+            )
+            .await;
+            if available.contains(&target) {
+                return Some((target, None));
+            }
+        }
+    }
+
+    let client_ip_for_strategy = match route.balance_strategy {
+        BalanceStrategy::IPHash => Some(client_ip),
+        _ => None,
+    };
+    let target = route
+        .balance_strategy
+        .select_target(
+            Arc::clone(&route.balancer_state),
+            Arc::clone(&route.target_addrs),
+            Arc::clone(&route.connection_counts),
+            Arc::clone(&route.request_limits),
+            route.max_requests_per_target,
+            route.resource_monitor.as_ref().map(Arc::clone),
+            route.target_weights.clone(),
+            route.health_statuses.as_ref().map(Arc::clone),
+            route.outlier_detector.as_ref().map(Arc::clone),
+            route.circuit_breaker.as_ref().map(Arc::clone),
+            excluded,
+            client_ip_for_strategy,
+        )
+        .await?;
+    let affinity_cookie = route
+        .session_affinity
+        .as_ref()
+        .map(|affinity| affinity.set_cookie_header(&target));
+    Some((target, affinity_cookie))
+}
+
+async fn record_outcome(route: &RouteContext, target_addr: &str, outcome: Result<(), FailureKind>) {
+    if let Some(outlier_detector) = &route.outlier_detector {
+        match outcome {
+            Ok(()) => outlier_detector.record_success(target_addr).await,
+            Err(kind) => outlier_detector.record_failure(target_addr, kind).await,
+        }
+    }
+    if let Some(circuit_breaker) = &route.circuit_breaker {
+        match outcome {
+            Ok(()) => circuit_breaker.record_success(target_addr).await,
+            Err(_) => circuit_breaker.record_failure(target_addr).await,
+        }
+    }
+}
+
+fn apply_request_modification(request: &mut RequestHead, rules: &[RequestModificationRule]) {
     for rule in rules {
         if let Some(ref contains) = rule.path_contains {
-            if path.contains(contains) {
+            if request.path.contains(contains) {
                 if let Some(ref new_path) = rule.rewrite_url {
-                    path = new_path.clone();
+                    request.path = new_path.clone();
                     break;
                 }
             }
         }
         for header in &rule.remove_headers {
-            headers.remove(header);
+            request.headers.remove(header);
+        }
+        for (key, value) in &rule.add_headers {
+            request.headers.set(key, value);
         }
-        headers.extend(rule.add_headers.clone());
     }
-    let mut modified_request = format!("{} {} HTTP/1.1\r\n", method, path);
-    for (key, value) in &headers {
-        modified_request.push_str(&format!("{}: {}\r\n", key, value));
+}
+
+// Answers from the cache, compressed when the route compresses the response
+// for this client. Compressed copies are cached alongside the entry so they
+// are only made once.
+async fn serve_from_cache<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    request: &RequestHead,
+    key: &str,
+    hit: CacheHit,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<()> {
+    let compression = negotiate_compression(route, request)
+        .filter(|_| !not_modified(request, &hit.response.head));
+    let Some(negotiated) = compression else {
+        return send_cached_response(stream, request, hit, deadline).await;
+    };
+    let encoding = negotiated.encoding.as_str();
+    if let Some(encoded) = route
+        .cache
+        .get_encoded(key, &request.headers, encoding)
+        .await
+    {
+        return send_cached_response(stream, request, encoded, deadline).await;
     }
-    modified_request.push_str("\r\n");
-    modified_request.push_str(body);
-    incoming.write_all(modified_request.as_bytes()).await?;
-    incoming.flush().await?;
-    Ok(())
+    let response = &hit.response;
+    let compressed = Some(negotiated)
+        .filter(|negotiated| negotiated.applies_to(&response.head, Some(response.body.len())))
+        .and_then(|negotiated| {
+            compress_response(route, negotiated, &response.head, &response.body)
+        });
+    let Some((head, body)) = compressed else {
+        return send_cached_response(stream, request, hit, deadline).await;
+    };
+    let response = CachedResponse { head, body };
+    route
+        .cache
+        .put_encoded(
+            key.to_string(),
+            &request.headers,
+            encoding,
+            response.clone(),
+        )
+        .await;
+    let hit = CacheHit { response, ..hit };
+    send_cached_response(stream, request, hit, deadline).await
 }
 
-async fn proxy_connection(
-    mut incoming: TcpStream,
-    target_addr: &str,
-    timeout: Duration,
-    connection_counts: Arc<Mutex<HashMap<String, usize>>>,
-    pool: Arc<Pool<TcpConnectionManager>>,
-    cache: Arc<Mutex<Cache>>,
-    cache_enabled_endpoints: Option<Vec<String>>,
-    rules: Option<Vec<RequestModificationRule>>,
+// Answers from the cache, with 304 Not Modified when the client's own
+// validators still match.
+async fn send_cached_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    request: &RequestHead,
+    hit: CacheHit,
+    deadline: &RequestDeadline<'_>,
 ) -> io::Result<()> {
-    if let Some(ref rules) = rules {
-        if !rules.is_empty() {
-            handle_request_modification(&mut incoming, rules).await?;
-        }
+    if not_modified(request, &hit.response.head) {
+        let head = not_modified_response(&hit.response.head);
+        return write_to_client(stream, &[&head.to_bytes()], deadline).await;
     }
-    let requested_endpoint = extract_endpoint_from_stream(&mut incoming).await?;
-    if let Some(ref cache_enabled_endpoints) = cache_enabled_endpoints {
-        if cache_enabled_endpoints.contains(&requested_endpoint) {
-            let cache_lock = cache.lock().await;
-            if let Some(cached_data) = cache_lock.get(&requested_endpoint).await {
-                return send_cached_response(incoming, &cached_data).await;
-            }
+    let mut head = hit.response.head;
+    head.headers.set("Age", &hit.age.as_secs().to_string());
+    write_to_client(stream, &[&head.to_bytes(), &hit.response.body], deadline).await
+}
+
+// Writes and flushes `parts` to the client within the idle timeout and what
+// is left of the request's total duration.
+async fn write_to_client<W: AsyncWrite + Unpin>(
+    client_writer: &mut W,
+    parts: &[&[u8]],
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<()> {
+    let write = async {
+        for part in parts {
+            client_writer.write_all(part).await?;
         }
-    }
-    let target = connect_to_target(&pool, timeout, target_addr).await?;
-    let result = proxy_traffic_and_cache_response(
-        incoming,
-        target,
-        target_addr,
-        &connection_counts,
-        cache.clone(),
-        requested_endpoint.clone(),
-        &cache_enabled_endpoints,
-    )
-    .await;
-    result
+        client_writer.flush().await
+    };
+    deadline
+        .run(TimeoutPhase::Idle, write)
+        .await
+        .map_err(ClientWriteError::wrap)
 }
 
-async fn extract_endpoint_from_stream(stream: &mut TcpStream) -> io::Result<String> {
-    let mut reader = BufReader::new(stream);
-    let mut first_line = String::new();
-    let bytes_read = reader.read_line(&mut first_line).await?;
-    if bytes_read == 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "EOF reached before completing read",
-        ));
-    }
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-    if parts.len() < 3 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid HTTP request line",
-        ));
-    }
-    let path = parts[1].to_string();
-    Ok(path)
+async fn send_error_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: &str,
+    message: &str,
+) -> io::Result<()> {
+    send_response(stream, status, "text/plain", message).await
 }
 
-async fn send_cached_response(mut stream: TcpStream, cached_data: &[u8]) -> io::Result<()> {
-    stream.write_all(cached_data).await?;
+async fn send_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
     stream.flush().await?;
     Ok(())
 }
 
 async fn connect_to_target(
-    pool: &Arc<Pool<TcpConnectionManager>>,
-    timeout: Duration,
+    route: &RouteContext,
     target_addr: &str,
+    deadline: &RequestDeadline<'_>,
 ) -> io::Result<TcpStream> {
-    let target_stream_future = pool.get();
-    match time::timeout(timeout, target_stream_future).await {
-        Ok(Ok(connection)) => {
+    let pool = route.pools.get(target_addr).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No connection pool for {}", target_addr),
+        )
+    })?;
+    let target_stream_future = async {
+        match pool.get().await {
+            Ok(connection) => Ok(connection.into_inner()),
+            Err(MobcError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                PhaseTimeout {
+                    phase: TimeoutPhase::UpstreamConnect,
+                    deadline_exceeded: false,
+                },
+            )),
+            Err(e) => Err(io::Error::other(format!(
+                "Failed to connect to {}: {:?}",
+                target_addr, e
+            ))),
+        }
+    };
+    match deadline
+        .run(TimeoutPhase::UpstreamConnect, target_stream_future)
+        .await
+    {
+        Ok(tcp_stream) => {
             info!("Connection established to {}", target_addr);
-            let tcp_stream = connection.into_inner();
             Ok(tcp_stream)
         }
-        Ok(Err(e)) => match e {
-            MobcError::Timeout => {
-                warn!("Connection to {} timed out", target_addr);
-                Err(io::Error::new(
-                    io::ErrorKind::TimedOut,
-                    "Connection timed out",
-                ))
-            }
-            _ => {
-                warn!("Failed to connect to {}: {:?}", target_addr, e);
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Failed to connect to {}: {:?}", target_addr, e),
-                ))
-            }
-        },
-        Err(_) => {
-            warn!("Connection attempt to {} timed out", target_addr);
-            Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                "Connection attempt timed out",
-            ))
+        Err(e) => {
+            warn!("Failed to connect to {}: {}", target_addr, e);
+            Err(e)
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn proxy_traffic_and_cache_response(
-    mut incoming: TcpStream,
-    mut target: TcpStream,
+    client_reader: BufReader<ReadHalf<'_>>,
+    mut client_writer: WriteHalf<'_>,
+    target: TcpStream,
     target_addr: &str,
-    connection_counts: &Arc<Mutex<HashMap<String, usize>>>,
-    cache: Arc<Mutex<Cache>>,
-    requested_endpoint: String,
-    cache_enabled_endpoints: &Option<Vec<String>>,
+    request: RequestHead,
+    affinity_cookie: Option<String>,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
 ) -> io::Result<()> {
-    {
-        let mut counts = connection_counts.lock().await;
-        *counts.entry(target_addr.to_string()).or_insert(0) += 1;
+    let _connection = track_connection(route, target_addr);
+
+    let cache_request = route
+        .cache_policy
+        .is_cacheable(&request)
+        .then(|| request.clone());
+    let proxy_result = exchange(
+        client_reader,
+        &mut client_writer,
+        target,
+        request,
+        affinity_cookie,
+        route,
+        deadline,
+    )
+    .await;
+
+    // A client that is slow to send its body, or that stops reading the
+    // response, says nothing about the target.
+    let client_failed = proxy_result.as_ref().err().is_some_and(|e| {
+        ClientWriteError::from_error(e).is_some()
+            || PhaseTimeout::from_error(e).is_some_and(|timeout| {
+                matches!(
+                    timeout.phase,
+                    TimeoutPhase::ClientHeader | TimeoutPhase::ClientBody
+                )
+            })
+    });
+    if !client_failed {
+        let outcome = match &proxy_result {
+            Ok((response, _)) if response.status >= 500 => {
+                Err(FailureKind::ServerError(response.status))
+            }
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(FailureKind::Timeout),
+            Err(_) => Err(FailureKind::ConnectFailure),
+        };
+        record_outcome(route, target_addr, outcome).await;
     }
 
-    let (mut ri, mut wi) = incoming.split();
-    let (mut ro, mut wo) = target.split();
+    // Cache the response if applicable
+    match proxy_result {
+        Ok((response, body)) => {
+            if let Some(request) = &cache_request {
+                store_in_cache(route, request, response, body).await;
+            }
+            info!(
+                "Proxy and cache operation completed successfully for {}",
+                target_addr
+            );
+        }
+        Err(e) => {
+            warn!("Proxy operation failed for {}: {:?}", target_addr, e);
+            let _ = fail_with_timeout(route, &mut client_writer, e).await;
+        }
+    }
 
-    // Read the first line of the request to extract the method and path
-    let mut reader = BufReader::new(&mut ri);
-    let mut first_line = String::new();
-    reader.read_line(&mut first_line).await?;
-    let mut parts = first_line.split_whitespace();
-    let method = parts.next().unwrap_or("GET");
-    let path = parts.next().unwrap_or("/");
+    Ok(())
+}
 
-    // Reconstruct the request line with the target address and extracted path
-    let modified_request_line = format!("{} {} HTTP/1.1\r\n", method, path);
-    wo.write_all(modified_request_line.as_bytes()).await?;
+// Counts a request in flight to a target for as long as it is held, however
+// the request ends.
+struct ConnectionGuard {
+    counts: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+    target_addr: String,
+}
 
-    // Forward the remaining headers and body
-    io::copy(&mut reader, &mut wo).await?;
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.target_addr) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
 
-    // Read the target response and forward it to the client
-    let mut target_response_buffer = Vec::new();
-    let proxy_result = read_and_write(&mut ro, &mut wi, &mut target_response_buffer).await;
+fn track_connection(route: &RouteContext, target_addr: &str) -> ConnectionGuard {
+    let mut counts = route.connection_counts.lock().unwrap();
+    *counts.entry(target_addr.to_string()).or_insert(0) += 1;
+    ConnectionGuard {
+        counts: Arc::clone(&route.connection_counts),
+        target_addr: target_addr.to_string(),
+    }
+}
 
-    // Cache the response if applicable
-    if let Ok(_) = proxy_result {
-        if cache_enabled_endpoints
-            .as_ref()
-            .map_or(false, |eps| eps.contains(&requested_endpoint))
-        {
-            let cache_lock = cache.lock().await;
-            cache_lock.put(requested_endpoint, target_response_buffer).await;
+async fn store_in_cache(
+    route: &RouteContext,
+    request: &RequestHead,
+    response: ResponseHead,
+    body: Vec<u8>,
+) {
+    if !route.cache_policy.is_cacheable(request) {
+        return;
+    }
+    let Some(key) = route.cache_policy.cache_key(request) else {
+        return;
+    };
+    let Some(freshness) = route.cache_policy.freshness(request, &response) else {
+        return;
+    };
+    if !is_complete_body(request, &response, &body) {
+        warn!(
+            "Not caching {}: the response from the target was cut short",
+            request.path
+        );
+        return;
+    }
+    route
+        .cache
+        .put(
+            key,
+            &request.headers,
+            CachedResponse {
+                head: response,
+                body,
+            },
+            freshness,
+        )
+        .await;
+}
+
+// Whether `body` is all of the response, as the target closing the connection
+// does not say whether it finished sending.
+fn is_complete_body(request: &RequestHead, response: &ResponseHead, body: &[u8]) -> bool {
+    if request.method == "HEAD" || matches!(response.status, 100..=199 | 204 | 304) {
+        return true;
+    }
+    if response.headers.get("Transfer-Encoding").is_some() {
+        let mut framing = ChunkedFraming::default();
+        return framing.feed(body).is_ok() && framing.is_done();
+    }
+    match response.headers.get("Content-Length") {
+        Some(length) => length.trim().parse::<usize>().ok() == Some(body.len()),
+        None => true,
+    }
+}
+
+// Forwards the request to the target and relays the response back, returning
+// the response exactly as the target sent it.
+async fn exchange(
+    mut client_reader: BufReader<ReadHalf<'_>>,
+    client_writer: &mut WriteHalf<'_>,
+    mut target: TcpStream,
+    mut request: RequestHead,
+    affinity_cookie: Option<String>,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<(ResponseHead, Vec<u8>)> {
+    let (ro, mut wo) = target.split();
+    let response_started = AtomicBool::new(false);
+    let body_length = match request.headers.get("Transfer-Encoding") {
+        Some(_) => None,
+        None => request
+            .headers
+            .get("Content-Length")
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .or(Some(0)),
+    };
+
+    // The response is read until the target closes the connection.
+    request.headers.set("Connection", "close");
+    deadline
+        .run(
+            TimeoutPhase::UpstreamFirstByte,
+            wo.write_all(&request.to_bytes()),
+        )
+        .await?;
+
+    // Only a body timeout before the response has started ends the exchange;
+    // other copy errors leave the target to answer or close on its own. A
+    // chunked body has no length to bound as a whole, so each read of it is
+    // bounded instead.
+    let forward_request_body = async {
+        let copied = match body_length {
+            Some(length) => {
+                let mut body = (&mut client_reader).take(length);
+                deadline
+                    .run(TimeoutPhase::ClientBody, io::copy(&mut body, &mut wo))
+                    .await
+            }
+            None => copy_chunked_body(&mut client_reader, &mut wo, deadline).await,
+        };
+        match copied {
+            Err(e)
+                if PhaseTimeout::from_error(&e).is_some()
+                    && !response_started.load(Ordering::Relaxed) =>
+            {
+                e
+            }
+            _ => future::pending::<io::Error>().await,
         }
-        info!("Proxy and cache operation completed successfully for {}", target_addr);
-    } else {
-        warn!("Proxy operation failed for {}: {:?}", target_addr, proxy_result.err().unwrap());
+    };
+
+    let relay = async {
+        let mut target_reader = BufReader::new(ro);
+        let response = deadline
+            .run(
+                TimeoutPhase::UpstreamFirstByte,
+                ResponseHead::read_from(&mut target_reader),
+            )
+            .await?;
+        response_started.store(true, Ordering::Relaxed);
+        relay_response(
+            &mut target_reader,
+            client_writer,
+            &request,
+            response,
+            affinity_cookie,
+            route,
+            deadline,
+        )
+        .await
+    };
+
+    tokio::select! {
+        result = relay => result,
+        e = forward_request_body => Err(e),
     }
+}
 
-    {
-        let mut counts = connection_counts.lock().await;
-        if let Some(count) = counts.get_mut(target_addr) {
-            *count = count.saturating_sub(1);
+// Copies a chunked request body up to and including its last chunk, or until
+// the client closes.
+async fn copy_chunked_body<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<u64> {
+    let mut framing = ChunkedFraming::default();
+    let mut buf = [0; 4096];
+    let mut total_written = 0;
+    while !framing.is_done() {
+        let n = deadline
+            .run(TimeoutPhase::ClientBody, reader.read(&mut buf))
+            .await?;
+        if n == 0 {
+            break;
         }
+        let n = framing.feed(&buf[0..n])?;
+        writer.write_all(&buf[0..n]).await?;
+        total_written += n as u64;
     }
+    Ok(total_written)
+}
 
-    Ok(())
+// Writes the response head, with the affinity cookie if any, and streams the
+// rest of the target's response to the client. A response the route
+// compresses for this client is read in full and sent compressed instead.
+// Returns the head as the target sent it and the body.
+async fn relay_response<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    target_reader: &mut R,
+    client_writer: &mut W,
+    request: &RequestHead,
+    response: ResponseHead,
+    affinity_cookie: Option<String>,
+    route: &RouteContext,
+    deadline: &RequestDeadline<'_>,
+) -> io::Result<(ResponseHead, Vec<u8>)> {
+    let mut body = Vec::new();
+    let compression = negotiate_compression(route, request)
+        .filter(|negotiated| negotiated.applies_to(&response, None));
+    let Some(negotiated) = compression else {
+        let mut client_response = response.clone();
+        if let Some(cookie) = &affinity_cookie {
+            client_response.headers.append("Set-Cookie", cookie);
+        }
+        write_to_client(client_writer, &[&client_response.to_bytes()], deadline).await?;
+        read_and_write(target_reader, client_writer, &mut body, deadline).await?;
+        return Ok((response, body));
+    };
+
+    let mut sink = io::sink();
+    read_and_write(target_reader, &mut sink, &mut body, deadline).await?;
+    let compressed = compress_response(route, negotiated, &response, &body);
+    let (mut client_response, client_body) = match &compressed {
+        Some((head, compressed_body)) => (head.clone(), compressed_body.as_slice()),
+        None => (response.clone(), body.as_slice()),
+    };
+    if let Some(cookie) = &affinity_cookie {
+        client_response.headers.append("Set-Cookie", cookie);
+    }
+    write_to_client(
+        client_writer,
+        &[&client_response.to_bytes(), client_body],
+        deadline,
+    )
+    .await?;
+    Ok((response, body))
+}
+
+fn negotiate_compression<'r>(
+    route: &'r RouteContext,
+    request: &RequestHead,
+) -> Option<Negotiated<'r>> {
+    route.compression.as_ref()?.negotiate(request)
 }
 
-async fn read_and_write(
-    reader: &mut ReadHalf<'_>,
-    writer: &mut WriteHalf<'_>,
+fn compress_response(
+    route: &RouteContext,
+    negotiated: Negotiated<'_>,
+    head: &ResponseHead,
+    body: &[u8],
+) -> Option<(ResponseHead, Vec<u8>)> {
+    let compressed = negotiated.compress(head, body)?;
+    route.metrics.increment(
+        "road47_compressed_responses_total",
+        &[("encoding", negotiated.encoding.as_str())],
+    );
+    Some(compressed)
+}
+
+async fn read_and_write<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
     buffer: &mut Vec<u8>,
+    deadline: &RequestDeadline<'_>,
 ) -> io::Result<u64> {
     let mut buf = [0; 4096];
     let mut total_written = 0;
     loop {
-        let n = reader.read(&mut buf).await?;
+        let n = deadline
+            .run(TimeoutPhase::Idle, reader.read(&mut buf))
+            .await?;
         if n == 0 {
             break;
         }
         buffer.extend_from_slice(&buf[0..n]);
-        writer.write_all(&buf[0..n]).await?;
+        deadline
+            .run(TimeoutPhase::Idle, writer.write_all(&buf[0..n]))
+            .await
+            .map_err(ClientWriteError::wrap)?;
         total_written += n as u64;
     }
     Ok(total_written)
-}
\ No newline at end of file
+}
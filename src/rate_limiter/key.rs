@@ -0,0 +1,275 @@
+use crate::cache_policy::split_path;
+use crate::http::RequestHead;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+enum KeyPart {
+    ClientIp,
+    Route,
+    Path,
+    Header(String),
+    Cookie(String),
+    JwtClaim(String),
+}
+
+impl KeyPart {
+    // Parts are named `ip`, `route`, `path`, `header:<name>`, `cookie:<name>`
+    // or `jwt:<claim>`.
+    fn parse(part: &str) -> Option<Self> {
+        match part.trim().split_once(':') {
+            Some(("header", name)) if !name.is_empty() => Some(KeyPart::Header(name.to_string())),
+            Some(("cookie", name)) if !name.is_empty() => Some(KeyPart::Cookie(name.to_string())),
+            Some(("jwt", claim)) if !claim.is_empty() => Some(KeyPart::JwtClaim(claim.to_string())),
+            Some(_) => None,
+            None => match part.trim() {
+                "ip" => Some(KeyPart::ClientIp),
+                "route" => Some(KeyPart::Route),
+                "path" => Some(KeyPart::Path),
+                _ => None,
+            },
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            KeyPart::ClientIp => "ip",
+            KeyPart::Route => "route",
+            KeyPart::Path => "path",
+            KeyPart::Header(_) => "header",
+            KeyPart::Cookie(_) => "cookie",
+            KeyPart::JwtClaim(_) => "jwt",
+        }
+    }
+}
+
+// What requests are counted together for rate limiting: the combination of
+// the configured parts, or the client IP for requests missing any of them.
+pub struct RateLimitKey {
+    parts: Vec<KeyPart>,
+    route: String,
+    jwt_secret: Option<Vec<u8>>,
+}
+
+impl RateLimitKey {
+    pub fn new(parts: &[String], jwt_secret: Option<&str>, route: &str) -> Self {
+        let parts = parts
+            .iter()
+            .filter_map(|part| {
+                let parsed = KeyPart::parse(part);
+                if parsed.is_none() {
+                    warn!("Ignoring unsupported rate limit key part {}", part);
+                }
+                parsed
+            })
+            .collect::<Vec<_>>();
+        let jwt_secret = jwt_secret.filter(|secret| !secret.is_empty());
+        // Claims of unsigned tokens are whatever the client wants them to be,
+        // so keying on them would give every request a bucket of its own.
+        let uses_jwt = parts
+            .iter()
+            .any(|part| matches!(part, KeyPart::JwtClaim(_)));
+        let parts = if uses_jwt && jwt_secret.is_none() {
+            warn!(
+                "Rate limit key for {} uses JWT claims without a jwt_secret; counting by client IP",
+                route
+            );
+            Vec::new()
+        } else {
+            parts
+        };
+        RateLimitKey {
+            parts,
+            route: route.to_string(),
+            jwt_secret: jwt_secret.map(|secret| secret.as_bytes().to_vec()),
+        }
+    }
+
+    pub fn extract(&self, request: &RequestHead, client_ip: &str) -> String {
+        let mut key = String::new();
+        for part in &self.parts {
+            let value = match part {
+                KeyPart::ClientIp => Some(client_ip.to_string()),
+                KeyPart::Route => Some(self.route.clone()),
+                KeyPart::Path => Some(split_path(&request.path).0.to_string()),
+                KeyPart::Header(name) => request.headers.get(name).map(str::to_string),
+                KeyPart::Cookie(name) => request.cookie(name).map(str::to_string),
+                KeyPart::JwtClaim(claim) => self.jwt_claim(request, claim),
+            };
+            let Some(value) = value.filter(|value| !value.is_empty()) else {
+                return format!("ip={}", client_ip);
+            };
+            if !key.is_empty() {
+                key.push('\n');
+            }
+            key.push_str(part.name());
+            key.push('=');
+            key.push_str(&value);
+        }
+        if key.is_empty() {
+            return format!("ip={}", client_ip);
+        }
+        key
+    }
+
+    // A claim of the bearer token, trusted only when the token is signed with
+    // the configured secret using HS256 and has not expired.
+    fn jwt_claim(&self, request: &RequestHead, claim: &str) -> Option<String> {
+        let authorization = request.headers.get("Authorization")?.trim();
+        let (scheme, token) = authorization.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Bearer") {
+            return None;
+        }
+        let mut segments = token.trim().split('.');
+        let (header, payload, signature) = (segments.next()?, segments.next()?, segments.next()?);
+        if segments.next().is_some() {
+            return None;
+        }
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+
+        let secret = self.jwt_secret.as_ref()?;
+        let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        if header.get("alg").and_then(Value::as_str) != Some("HS256") {
+            return None;
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let mut mac = HmacSha256::new_from_slice(secret).ok()?;
+        mac.update(token.trim().rsplit_once('.')?.0.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        if claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .is_some_and(|expires| expires <= now)
+        {
+            return None;
+        }
+
+        match claims.get(claim)? {
+            Value::String(value) => Some(value.clone()),
+            Value::Null => None,
+            value => Some(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HmacSha256, RateLimitKey};
+    use crate::http::{Headers, RequestHead};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use hmac::Mac;
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &str = "secret";
+
+    fn key(parts: &[&str], jwt_secret: Option<&str>) -> RateLimitKey {
+        let parts: Vec<String> = parts.iter().map(|part| part.to_string()).collect();
+        RateLimitKey::new(&parts, jwt_secret, "api")
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> RequestHead {
+        let mut headers = Headers::default();
+        for (name, value) in pairs {
+            headers.append(name, value);
+        }
+        RequestHead {
+            method: "GET".to_string(),
+            path: "/orders?page=2".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers,
+        }
+    }
+
+    fn token(alg: &str, claims: serde_json::Value, secret: &str) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{}.{}", header, payload);
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", signed, signature)
+    }
+
+    fn bearer(token: &str) -> RequestHead {
+        request(&[("Authorization", &format!("Bearer {}", token))])
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn combines_configured_parts() {
+        let key = key(&["route", "path", "header:X-Api-Key", "cookie:team"], None);
+        let request = request(&[("X-Api-Key", "k1"), ("Cookie", "a=1; team=blue")]);
+        assert_eq!(
+            key.extract(&request, "10.0.0.1"),
+            "route=api\npath=/orders\nheader=k1\ncookie=blue"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_ip_when_a_part_is_missing() {
+        let key = key(&["header:X-Api-Key", "cookie:team"], None);
+        let request = request(&[("X-Api-Key", "k1")]);
+        assert_eq!(key.extract(&request, "10.0.0.1"), "ip=10.0.0.1");
+    }
+
+    #[test]
+    fn ignores_unsupported_parts() {
+        let key = key(&["query:page", "ip"], None);
+        assert_eq!(key.extract(&request(&[]), "10.0.0.1"), "ip=10.0.0.1");
+    }
+
+    #[test]
+    fn uses_claims_of_signed_tokens() {
+        let key = key(&["jwt:tenant"], Some(SECRET));
+        let valid = token(
+            "HS256",
+            json!({ "tenant": "acme", "exp": now() + 60 }),
+            SECRET,
+        );
+        assert_eq!(key.extract(&bearer(&valid), "10.0.0.1"), "jwt=acme");
+        let numeric = token("HS256", json!({ "tenant": 42 }), SECRET);
+        assert_eq!(key.extract(&bearer(&numeric), "10.0.0.1"), "jwt=42");
+    }
+
+    #[test]
+    fn falls_back_to_ip_for_forged_or_expired_tokens() {
+        let key = key(&["jwt:tenant"], Some(SECRET));
+        let forged = token("HS256", json!({ "tenant": "acme" }), "guessed");
+        assert_eq!(key.extract(&bearer(&forged), "10.0.0.1"), "ip=10.0.0.1");
+        let expired = token(
+            "HS256",
+            json!({ "tenant": "acme", "exp": now() - 1 }),
+            SECRET,
+        );
+        assert_eq!(key.extract(&bearer(&expired), "10.0.0.1"), "ip=10.0.0.1");
+        let other_alg = token("HS512", json!({ "tenant": "acme" }), SECRET);
+        assert_eq!(key.extract(&bearer(&other_alg), "10.0.0.1"), "ip=10.0.0.1");
+        let valid = token("HS256", json!({ "tenant": "acme" }), SECRET);
+        let basic = request(&[("Authorization", &format!("Basic {}", valid))]);
+        assert_eq!(key.extract(&basic, "10.0.0.1"), "ip=10.0.0.1");
+    }
+
+    #[test]
+    fn does_not_trust_claims_without_a_secret() {
+        let key = key(&["header:X-Api-Key", "jwt:tenant"], None);
+        let valid = token("HS256", json!({ "tenant": "acme" }), SECRET);
+        let mut request = bearer(&valid);
+        request.headers.append("X-Api-Key", "k1");
+        assert_eq!(key.extract(&request, "10.0.0.1"), "ip=10.0.0.1");
+    }
+}
@@ -24,11 +24,11 @@ impl RateLimiter for LeakyBucketRateLimiter {
     fn allow(&self, key: &str) -> bool {
         let mut requests = self.requests.lock().unwrap();
         let now = Instant::now();
-        let queue = requests.entry(key.to_owned()).or_insert_with(VecDeque::new);
+        let queue = requests.entry(key.to_owned()).or_default();
 
         while queue
             .front()
-            .map_or(false, |&t| now.duration_since(t) > self.leak_rate)
+            .is_some_and(|&t| now.duration_since(t) > self.leak_rate)
         {
             queue.pop_front();
         }
@@ -1,5 +1,7 @@
 mod fixed_window;
+mod key;
 mod leaky_bucket;
+mod policy;
 mod sliding_window_counter;
 mod sliding_window_log;
 mod token_bucket;
@@ -13,6 +15,9 @@ use crate::rate_limiter::token_bucket::TokenBucketRateLimiter;
 use log::error;
 use std::time::Duration;
 
+pub use key::RateLimitKey;
+pub use policy::{RateLimitPolicies, RateLimitPolicy};
+
 pub trait RateLimiter: Send + Sync {
     fn allow(&self, key: &str) -> bool;
 }
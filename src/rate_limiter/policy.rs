@@ -0,0 +1,62 @@
+use crate::config::RateLimitingConfig;
+use crate::http::RequestHead;
+use crate::rate_limiter::{create_rate_limiter, RateLimitKey, RateLimiter};
+use std::sync::Arc;
+
+// A named rate limit: a limiter and what requests it counts together. The
+// limiter may be shared between routes, with each route extracting keys for
+// its own requests.
+pub struct RateLimitPolicy {
+    pub name: String,
+    limiter: Arc<Box<dyn RateLimiter + Send + Sync>>,
+    key: RateLimitKey,
+}
+
+impl RateLimitPolicy {
+    pub fn new(name: String, config: &RateLimitingConfig, route: &str) -> Self {
+        let limiter = Arc::new(create_rate_limiter(Some(config.clone())));
+        RateLimitPolicy::shared(name, limiter, config, route)
+    }
+
+    // A policy counting against a limiter other routes use too.
+    pub fn shared(
+        name: String,
+        limiter: Arc<Box<dyn RateLimiter + Send + Sync>>,
+        config: &RateLimitingConfig,
+        route: &str,
+    ) -> Self {
+        RateLimitPolicy {
+            name,
+            limiter,
+            key: RateLimitKey::new(
+                config.key.as_deref().unwrap_or_default(),
+                config.jwt_secret.as_deref(),
+                route,
+            ),
+        }
+    }
+
+    pub fn allows(&self, request: &RequestHead, client_ip: &str) -> bool {
+        self.limiter.allow(&self.key.extract(request, client_ip))
+    }
+}
+
+// Rate limits applied one after another. A request is counted by each policy
+// up to the first that rejects it.
+#[derive(Default)]
+pub struct RateLimitPolicies {
+    policies: Vec<RateLimitPolicy>,
+}
+
+impl RateLimitPolicies {
+    pub fn new(policies: Vec<RateLimitPolicy>) -> Self {
+        RateLimitPolicies { policies }
+    }
+
+    // The policy rejecting the request, if any.
+    pub fn check(&self, request: &RequestHead, client_ip: &str) -> Option<&RateLimitPolicy> {
+        self.policies
+            .iter()
+            .find(|policy| !policy.allows(request, client_ip))
+    }
+}
@@ -37,7 +37,7 @@ impl RateLimiter for SlidingWindowCounterRateLimiter {
         let now = Instant::now();
         let windows_to_keep = now - self.window_size;
 
-        let entry = windows.entry(key.to_owned()).or_insert_with(Vec::new);
+        let entry = windows.entry(key.to_owned()).or_default();
         entry.retain(|window| window.start >= windows_to_keep);
 
         if let Some(current_window) = entry.last_mut() {
@@ -25,7 +25,7 @@ impl SlidingWindowLogRateLimiter {
             let now = Instant::now();
             while times
                 .front()
-                .map_or(false, |&t| now.duration_since(t) > self.window)
+                .is_some_and(|&t| now.duration_since(t) > self.window)
             {
                 times.pop_front();
             }
@@ -40,7 +40,7 @@ impl RateLimiter for SlidingWindowLogRateLimiter {
         let now = Instant::now();
         self.cleanup(key);
 
-        let entry = requests.entry(key.to_owned()).or_insert_with(VecDeque::new);
+        let entry = requests.entry(key.to_owned()).or_default();
 
         if (entry.len() as u32) < self.limit {
             entry.push_back(now);
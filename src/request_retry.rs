@@ -0,0 +1,90 @@
+use crate::config::{RequestRetryConfig, RetryStrategyConfig};
+use crate::retry::create_strategy;
+use crate::retry_strategy::RetryStrategy;
+use log::warn;
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: usize = 2;
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+const DEFAULT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+const DEFAULT_RETRY_STATUSES: [u16; 3] = [502, 503, 504];
+
+#[derive(Clone, Copy, Debug)]
+pub enum RetryCondition {
+    ConnectFailure,
+    Reset,
+    Timeout,
+    Status(u16),
+}
+
+// Decides whether a failed request may be replayed against another target.
+// Only idempotent methods are retried by default, and only when the request
+// body is small enough to be buffered for replay.
+pub struct RequestRetryPolicy {
+    pub max_retries: usize,
+    pub max_body_bytes: usize,
+    pub per_try_timeout: Option<Duration>,
+    methods: Vec<String>,
+    retry_statuses: Vec<u16>,
+    retry_on_connect_failure: bool,
+    retry_on_reset: bool,
+    retry_on_timeout: bool,
+    backoff: RetryStrategyConfig,
+}
+
+impl RequestRetryPolicy {
+    pub fn new(config: &RequestRetryConfig, default_backoff: &RetryStrategyConfig) -> Self {
+        let methods = match &config.methods {
+            Some(methods) => methods.iter().map(|m| m.to_uppercase()).collect(),
+            None => DEFAULT_METHODS.iter().map(|m| m.to_string()).collect(),
+        };
+        let retry_on: Vec<String> = match &config.retry_on {
+            Some(conditions) => conditions.iter().map(|c| c.to_lowercase()).collect(),
+            None => vec![
+                "connect-failure".to_string(),
+                "reset".to_string(),
+                "timeout".to_string(),
+            ],
+        };
+        for condition in &retry_on {
+            if !matches!(condition.as_str(), "connect-failure" | "reset" | "timeout") {
+                warn!("Ignoring unknown retry_on condition: {}", condition);
+            }
+        }
+
+        RequestRetryPolicy {
+            max_retries: config.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            max_body_bytes: config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+            per_try_timeout: config.per_try_timeout_seconds.map(Duration::from_secs),
+            methods,
+            retry_statuses: config
+                .retry_statuses
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RETRY_STATUSES.to_vec()),
+            retry_on_connect_failure: retry_on.iter().any(|c| c == "connect-failure"),
+            retry_on_reset: retry_on.iter().any(|c| c == "reset"),
+            retry_on_timeout: retry_on.iter().any(|c| c == "timeout"),
+            backoff: config
+                .backoff
+                .clone()
+                .unwrap_or_else(|| default_backoff.clone()),
+        }
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    pub fn should_retry(&self, condition: RetryCondition) -> bool {
+        match condition {
+            RetryCondition::ConnectFailure => self.retry_on_connect_failure,
+            RetryCondition::Reset => self.retry_on_reset,
+            RetryCondition::Timeout => self.retry_on_timeout,
+            RetryCondition::Status(status) => self.retry_statuses.contains(&status),
+        }
+    }
+
+    pub fn strategy(&self) -> Box<dyn RetryStrategy> {
+        create_strategy(&self.backoff)
+    }
+}
@@ -0,0 +1,157 @@
+use crate::config::{ResourceCheckConfig, ResourceEndpoints};
+use float_ord::FloatOrd;
+use futures::future::join_all;
+use log::warn;
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio::time::interval;
+
+const DEFAULT_INTERVAL_SECONDS: u64 = 5;
+const DEFAULT_TIMEOUT_SECONDS: u64 = 2;
+
+struct ResourceSample {
+    score: f64,
+    fetched_at: Instant,
+}
+
+pub struct ResourceMonitor {
+    client: Client,
+    endpoints: HashMap<String, String>,
+    score_fields: HashMap<String, f64>,
+    interval: Duration,
+    timeout: Duration,
+    max_staleness: Duration,
+    samples: RwLock<HashMap<String, ResourceSample>>,
+}
+
+impl ResourceMonitor {
+    pub fn new(
+        target_addrs: &[String],
+        resource_endpoints: &ResourceEndpoints,
+        config: Option<&ResourceCheckConfig>,
+    ) -> Self {
+        // A plain list is matched to targets by their position in `target_addrs`.
+        let endpoints = match resource_endpoints {
+            ResourceEndpoints::List(urls) => target_addrs
+                .iter()
+                .cloned()
+                .zip(urls.iter().cloned())
+                .collect(),
+            ResourceEndpoints::Map(urls) => urls.clone(),
+        };
+
+        let interval_seconds = config
+            .and_then(|c| c.interval_seconds)
+            .unwrap_or(DEFAULT_INTERVAL_SECONDS)
+            .max(1);
+        let timeout_seconds = config
+            .and_then(|c| c.timeout_seconds)
+            .unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+        let max_staleness_seconds = config
+            .and_then(|c| c.max_staleness_seconds)
+            .unwrap_or(interval_seconds * 3);
+
+        let score_fields = match config.and_then(|c| c.score_fields.clone()) {
+            Some(fields) => fields,
+            None => HashMap::from([
+                (
+                    "cpu_usage_percent".to_string(),
+                    config.and_then(|c| c.cpu_weight).unwrap_or(1.0),
+                ),
+                (
+                    "memory_usage_percent".to_string(),
+                    config.and_then(|c| c.memory_weight).unwrap_or(1.0),
+                ),
+            ]),
+        };
+
+        ResourceMonitor {
+            client: Client::new(),
+            endpoints,
+            score_fields,
+            interval: Duration::from_secs(interval_seconds),
+            timeout: Duration::from_secs(timeout_seconds),
+            max_staleness: Duration::from_secs(max_staleness_seconds),
+            samples: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut tick_interval = interval(self.interval);
+        loop {
+            tick_interval.tick().await;
+            self.poll().await;
+        }
+    }
+
+    async fn poll(&self) {
+        let fetches = self
+            .endpoints
+            .iter()
+            .map(|(addr, endpoint)| async move { (addr, self.fetch_score(endpoint).await) });
+        let results = join_all(fetches).await;
+
+        let now = Instant::now();
+        let mut samples = self.samples.write().await;
+        for (addr, result) in results {
+            match result {
+                Some(score) => {
+                    samples.insert(
+                        addr.clone(),
+                        ResourceSample {
+                            score,
+                            fetched_at: now,
+                        },
+                    );
+                }
+                None => warn!("Failed to collect resource usage for {}", addr),
+            }
+        }
+    }
+
+    async fn fetch_score(&self, endpoint: &str) -> Option<f64> {
+        let response = self
+            .client
+            .get(endpoint)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .ok()?;
+        let body = response.json::<Value>().await.ok()?;
+
+        let mut score = 0.0;
+        for (field, weight) in &self.score_fields {
+            score += lookup_number(&body, field)? * weight;
+        }
+        Some(score)
+    }
+
+    // Picks the candidate with the lowest fresh score. Targets without a sample,
+    // or whose last sample is older than the staleness limit, are skipped.
+    pub async fn least_loaded(&self, addrs: &VecDeque<String>) -> Option<String> {
+        let now = Instant::now();
+        let samples = self.samples.read().await;
+        addrs
+            .iter()
+            .filter_map(|addr| {
+                let sample = samples.get(addr)?;
+                if now.duration_since(sample.fetched_at) > self.max_staleness {
+                    return None;
+                }
+                Some((addr, FloatOrd(sample.score)))
+            })
+            .min_by_key(|(_, score)| *score)
+            .map(|(addr, _)| addr.clone())
+    }
+}
+
+// Resolves a dotted path such as `stats.cpu.load` inside a JSON document.
+fn lookup_number(value: &Value, path: &str) -> Option<f64> {
+    path.split('.')
+        .try_fold(value, |current, key| current.get(key))?
+        .as_f64()
+}
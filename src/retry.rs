@@ -1,13 +1,27 @@
 use crate::config::RetryStrategyConfig;
 use crate::config::StrategyType;
+use crate::retry_budget::RetryBudget;
 use crate::retry_strategy::*;
-use futures::future::{select_ok, BoxFuture};
+use futures::future::{select, select_ok, BoxFuture, Either};
 use std::io::{self, Error, ErrorKind};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::time::{sleep, timeout};
 
-fn create_strategy(config: &RetryStrategyConfig) -> Box<dyn RetryStrategy> {
+pub fn create_strategy(config: &RetryStrategyConfig) -> Box<dyn RetryStrategy> {
+    Box::new(BoundedStrategy {
+        inner: create_base_strategy(config),
+        min_delay: config.delay_floor_millis.map(Duration::from_millis),
+        max_delay: config.delay_cap_millis.map(Duration::from_millis),
+        max_retry_after: Duration::from_secs(config.max_delay_secs),
+        jitter_fraction: config.jitter_percent.map(|percent| percent / 100.0),
+        deadline: config
+            .deadline_millis
+            .map(|millis| Instant::now() + Duration::from_millis(millis)),
+    })
+}
+
+fn create_base_strategy(config: &RetryStrategyConfig) -> Box<dyn RetryStrategy> {
     match config.strategy_type {
         StrategyType::FixedDelay => Box::new(FixedDelayStrategy {
             delay_duration: Duration::from_millis(config.initial_delay_millis),
@@ -53,16 +67,28 @@ fn create_strategy(config: &RetryStrategyConfig) -> Box<dyn RetryStrategy> {
             multiplier: config.multiplier.unwrap_or(2.0),
             max_delay: Duration::from_secs(config.max_delay_secs),
         }),
+        StrategyType::DecorrelatedJitter => Box::new(DecorrelatedJitterStrategy::new(
+            Duration::from_millis(config.initial_delay_millis),
+            Duration::from_secs(config.max_delay_secs),
+        )),
+        StrategyType::FullJitter => Box::new(FullJitterStrategy {
+            base_delay: Duration::from_millis(config.initial_delay_millis),
+            max_delay: Duration::from_secs(config.max_delay_secs),
+        }),
     }
 }
 
 pub async fn connect_with_retry(
     server_addresses: &[String],
     config: RetryStrategyConfig,
+    retry_budget: Option<&RetryBudget>,
 ) -> io::Result<TcpStream> {
     let strategy = create_strategy(&config);
     let max_attempts = config.max_attempts;
-    let timeout_secs = config.timeout_secs;
+    let connect_timeout = match config.connect_timeout_millis {
+        Some(millis) => Duration::from_millis(millis),
+        None => Duration::from_secs(config.timeout_secs),
+    };
     let mut attempts = 0;
 
     while strategy.should_retry(attempts, max_attempts) {
@@ -71,12 +97,7 @@ pub async fn connect_with_retry(
             .map(|address| {
                 let address_cloned = address.clone();
                 Box::pin(async move {
-                    match timeout(
-                        Duration::from_secs(timeout_secs),
-                        TcpStream::connect(&address_cloned),
-                    )
-                    .await
-                    {
+                    match timeout(connect_timeout, TcpStream::connect(&address_cloned)).await {
                         Ok(Ok(stream)) => {
                             println!("Successfully connected to {}", address_cloned);
                             Ok(stream)
@@ -94,18 +115,64 @@ pub async fn connect_with_retry(
             })
             .collect();
 
-        match select_ok(connect_futures).await {
+        let context = match select_ok(connect_futures).await {
             Ok((stream, _)) => return Ok(stream),
-            Err(e) => println!("All connection attempts failed on this iteration: {:?}", e),
+            Err(e) => {
+                println!("All connection attempts failed on this iteration: {:?}", e);
+                RetryContext::from_error(&e)
+            }
+        };
+
+        let Some(delay) = strategy.delay_with_context(attempts, &context) else {
+            return Err(Error::other("Target asked to back off for too long"));
+        };
+
+        // Only spend the budget when another attempt would actually follow.
+        if let Some(budget) = retry_budget {
+            if strategy.should_retry(attempts + 1, max_attempts)
+                && !budget_allows_retry(budget, server_addresses).await
+            {
+                return Err(Error::other("Retry budget exhausted"));
+            }
         }
 
-        let delay = strategy.delay(attempts);
         sleep(delay).await;
         attempts += 1;
     }
 
-    Err(Error::new(
-        ErrorKind::Other,
-        "Failed to connect after multiple attempts",
-    ))
-}
\ No newline at end of file
+    Err(Error::other("Failed to connect after multiple attempts"))
+}
+
+async fn budget_allows_retry(budget: &RetryBudget, server_addresses: &[String]) -> bool {
+    for address in server_addresses {
+        if budget.try_withdraw(address).await {
+            return true;
+        }
+    }
+    false
+}
+
+// Races `primary` against `hedge`, which is only started once `delay` has
+// passed. The first to succeed wins and the other is dropped, cancelling it.
+// A hedge that decides not to send anything yields None, leaving the result
+// to the primary; when both fail, the later failure is returned.
+pub async fn race_hedged<'a, T, E>(
+    primary: BoxFuture<'a, Result<T, E>>,
+    hedge: BoxFuture<'a, Option<Result<T, E>>>,
+    delay: Duration,
+) -> Result<T, E>
+where
+    T: Send + 'a,
+    E: Send + 'a,
+{
+    let delayed_hedge: BoxFuture<'a, Option<Result<T, E>>> = Box::pin(async move {
+        sleep(delay).await;
+        hedge.await
+    });
+    match select(primary, delayed_hedge).await {
+        Either::Left((Ok(value), _)) => Ok(value),
+        Either::Left((Err(error), hedge)) => hedge.await.unwrap_or(Err(error)),
+        Either::Right((Some(Ok(value)), _)) => Ok(value),
+        Either::Right((_, primary)) => primary.await,
+    }
+}
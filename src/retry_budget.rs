@@ -0,0 +1,111 @@
+use crate::config::RetryBudgetConfig;
+use crate::metrics::Metrics;
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DEFAULT_RETRY_PERCENT: f64 = 20.0;
+const DEFAULT_MIN_RETRIES: usize = 3;
+const DEFAULT_WINDOW_SECONDS: u64 = 10;
+const BUCKETS_PER_WINDOW: u32 = 10;
+
+struct Bucket {
+    started: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+#[derive(Default)]
+struct TargetBudget {
+    buckets: VecDeque<Bucket>,
+}
+
+impl TargetBudget {
+    fn current_bucket(&mut self, now: Instant, window: Duration) -> &mut Bucket {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| now.duration_since(bucket.started) >= window)
+        {
+            self.buckets.pop_front();
+        }
+        let bucket_width = window / BUCKETS_PER_WINDOW;
+        let needs_bucket = self
+            .buckets
+            .back()
+            .is_none_or(|bucket| now.duration_since(bucket.started) >= bucket_width);
+        if needs_bucket {
+            self.buckets.push_back(Bucket {
+                started: now,
+                requests: 0,
+                retries: 0,
+            });
+        }
+        self.buckets.back_mut().unwrap()
+    }
+
+    fn totals(&self) -> (usize, usize) {
+        self.buckets
+            .iter()
+            .fold((0, 0), |(requests, retries), bucket| {
+                (requests + bucket.requests, retries + bucket.retries)
+            })
+    }
+}
+
+// Caps retries against a target to a share of the original requests it has
+// recently received, so that during an outage retries cannot multiply the
+// load. A few retries on top keep them possible when traffic is light.
+pub struct RetryBudget {
+    retry_ratio: f64,
+    min_retries: usize,
+    window: Duration,
+    targets: Mutex<HashMap<String, TargetBudget>>,
+    metrics: Arc<Metrics>,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryBudgetConfig, metrics: Arc<Metrics>) -> Self {
+        RetryBudget {
+            retry_ratio: config.retry_percent.unwrap_or(DEFAULT_RETRY_PERCENT) / 100.0,
+            min_retries: config.min_retries.unwrap_or(DEFAULT_MIN_RETRIES),
+            window: Duration::from_secs(config.window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS)),
+            targets: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    // Counts a request sent to the target for the first time; retries and
+    // hedges are not counted.
+    pub async fn record_request(&self, target: &str) {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().await;
+        let budget = targets.entry(target.to_string()).or_default();
+        budget.current_bucket(now, self.window).requests += 1;
+    }
+
+    // Takes one retry from the target's budget, returning false when the
+    // budget is spent.
+    pub async fn try_withdraw(&self, target: &str) -> bool {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().await;
+        let budget = targets.entry(target.to_string()).or_default();
+        budget.current_bucket(now, self.window);
+
+        let (requests, retries) = budget.totals();
+        let allowed = (requests as f64 * self.retry_ratio) as usize + self.min_retries;
+        if retries >= allowed {
+            warn!(
+                "Retry budget for {} exhausted ({} retries for {} requests)",
+                target, retries, requests
+            );
+            self.metrics
+                .increment("road47_retry_budget_exhausted_total", &[("target", target)]);
+            return false;
+        }
+        budget.current_bucket(now, self.window).retries += 1;
+        true
+    }
+}
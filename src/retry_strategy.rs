@@ -1,10 +1,57 @@
 use rand::{thread_rng, Rng};
+use std::io;
 use std::ops::Mul;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// What caused the previous attempt to fail, for strategies that adapt to it.
+#[derive(Clone, Debug, Default)]
+pub struct RetryContext {
+    pub error_kind: Option<io::ErrorKind>,
+    pub status: Option<u16>,
+    pub retry_after: Option<Duration>,
+}
+
+impl RetryContext {
+    pub fn from_error(error: &io::Error) -> Self {
+        RetryContext {
+            error_kind: Some(error.kind()),
+            ..Self::default()
+        }
+    }
+
+    // `retry_after` is the raw `Retry-After` header value, either a number of
+    // seconds or an HTTP date.
+    pub fn from_response(status: u16, retry_after: Option<&str>) -> Self {
+        RetryContext {
+            status: Some(status),
+            retry_after: retry_after.and_then(parse_retry_after),
+            ..Self::default()
+        }
+    }
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
 
 pub trait RetryStrategy: Send {
     fn delay(&self, attempt: usize) -> Duration;
     fn should_retry(&self, attempt: usize, max_attempts: usize) -> bool;
+
+    // A server-provided `Retry-After` takes precedence over the computed delay.
+    // None means the next attempt should not be made at all.
+    fn delay_with_context(&self, attempt: usize, context: &RetryContext) -> Option<Duration> {
+        Some(context.retry_after.unwrap_or_else(|| self.delay(attempt)))
+    }
 }
 
 pub struct FixedDelayStrategy {
@@ -185,3 +232,124 @@ impl RetryStrategy for JitterBackoffStrategy {
         attempt < max_attempts
     }
 }
+
+// AWS-style decorrelated jitter: each delay is drawn between the base delay
+// and three times the previous one, capped at `max_delay`.
+pub struct DecorrelatedJitterStrategy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub previous: Mutex<Duration>,
+}
+
+impl DecorrelatedJitterStrategy {
+    pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        DecorrelatedJitterStrategy {
+            base_delay,
+            max_delay,
+            previous: Mutex::new(base_delay),
+        }
+    }
+}
+
+impl RetryStrategy for DecorrelatedJitterStrategy {
+    fn delay(&self, attempt: usize) -> Duration {
+        let mut previous = self.previous.lock().unwrap();
+        if attempt == 0 {
+            *previous = self.base_delay;
+        }
+        let upper = previous.mul(3).max(self.base_delay);
+        let delay = if upper > self.base_delay {
+            thread_rng().gen_range(self.base_delay..=upper)
+        } else {
+            self.base_delay
+        };
+        *previous = std::cmp::min(delay, self.max_delay);
+        *previous
+    }
+
+    fn should_retry(&self, attempt: usize, max_attempts: usize) -> bool {
+        attempt < max_attempts
+    }
+}
+
+// "Full jitter": a uniformly random delay up to the capped exponential backoff.
+pub struct FullJitterStrategy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryStrategy for FullJitterStrategy {
+    fn delay(&self, attempt: usize) -> Duration {
+        let exp_delay = self.base_delay.mul_f64(2_f64.powi(attempt.min(32) as i32));
+        let ceiling = std::cmp::min(exp_delay, self.max_delay);
+        if ceiling.is_zero() {
+            return Duration::ZERO;
+        }
+        thread_rng().gen_range(Duration::ZERO..=ceiling)
+    }
+
+    fn should_retry(&self, attempt: usize, max_attempts: usize) -> bool {
+        attempt < max_attempts
+    }
+}
+
+// Wraps any strategy to clamp its delays, add proportional jitter, and stop
+// retrying once an overall deadline has passed. A server's `Retry-After` is
+// honoured as it is, or not retried at all when longer than `max_retry_after`.
+pub struct BoundedStrategy {
+    pub inner: Box<dyn RetryStrategy>,
+    pub min_delay: Option<Duration>,
+    pub max_delay: Option<Duration>,
+    pub max_retry_after: Duration,
+    pub jitter_fraction: Option<f64>,
+    pub deadline: Option<Instant>,
+}
+
+impl BoundedStrategy {
+    fn bound(&self, delay: Duration) -> Duration {
+        let mut delay = delay;
+        if let Some(fraction) = self.jitter_fraction.filter(|fraction| *fraction > 0.0) {
+            let spread = delay.mul_f64(fraction);
+            let low = delay.saturating_sub(spread);
+            delay = thread_rng().gen_range(low..=delay + spread);
+        }
+        if let Some(min_delay) = self.min_delay {
+            delay = delay.max(min_delay);
+        }
+        if let Some(max_delay) = self.max_delay {
+            delay = delay.min(max_delay);
+        }
+        self.until_deadline(delay)
+    }
+
+    fn until_deadline(&self, delay: Duration) -> Duration {
+        match self.deadline {
+            Some(deadline) => delay.min(deadline.saturating_duration_since(Instant::now())),
+            None => delay,
+        }
+    }
+}
+
+impl RetryStrategy for BoundedStrategy {
+    fn delay(&self, attempt: usize) -> Duration {
+        self.bound(self.inner.delay(attempt))
+    }
+
+    fn should_retry(&self, attempt: usize, max_attempts: usize) -> bool {
+        let before_deadline = self
+            .deadline
+            .is_none_or(|deadline| Instant::now() < deadline);
+        before_deadline && self.inner.should_retry(attempt, max_attempts)
+    }
+
+    fn delay_with_context(&self, attempt: usize, context: &RetryContext) -> Option<Duration> {
+        match context.retry_after {
+            Some(retry_after) if retry_after > self.max_retry_after => None,
+            Some(retry_after) => Some(self.until_deadline(retry_after)),
+            None => self
+                .inner
+                .delay_with_context(attempt, context)
+                .map(|delay| self.bound(delay)),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::config::SessionAffinityConfig;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DEFAULT_COOKIE_NAME: &str = "road47_affinity";
+
+pub struct SessionAffinity {
+    cookie_name: String,
+    secret: Option<Vec<u8>>,
+    max_age_seconds: Option<u64>,
+    path: String,
+    secure: bool,
+    http_only: bool,
+}
+
+impl SessionAffinity {
+    pub fn new(config: &SessionAffinityConfig) -> Self {
+        SessionAffinity {
+            cookie_name: config
+                .cookie_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_COOKIE_NAME.to_string()),
+            secret: config.secret.as_ref().map(|s| s.as_bytes().to_vec()),
+            max_age_seconds: config.max_age_seconds,
+            path: config.path.clone().unwrap_or_else(|| "/".to_string()),
+            secure: config.secure.unwrap_or(false),
+            http_only: config.http_only.unwrap_or(true),
+        }
+    }
+
+    pub fn cookie_name(&self) -> &str {
+        &self.cookie_name
+    }
+
+    // Returns the target named by the cookie, or `None` when a signed cookie
+    // does not carry a valid signature.
+    pub fn target_from_cookie(&self, value: &str) -> Option<String> {
+        match &self.secret {
+            Some(secret) => {
+                let (target, signature) = value.rsplit_once('.')?;
+                let signature = hex::decode(signature).ok()?;
+                let mut mac = HmacSha256::new_from_slice(secret).ok()?;
+                mac.update(target.as_bytes());
+                mac.verify_slice(&signature).ok()?;
+                Some(target.to_string())
+            }
+            None => Some(value.to_string()),
+        }
+    }
+
+    pub fn set_cookie_header(&self, target: &str) -> String {
+        let value = match &self.secret {
+            Some(secret) => {
+                // HMAC accepts keys of any length, so this cannot fail.
+                let mut mac = HmacSha256::new_from_slice(secret).expect("valid HMAC key");
+                mac.update(target.as_bytes());
+                let signature = hex::encode(mac.finalize().into_bytes());
+                format!("{}.{}", target, signature)
+            }
+            None => target.to_string(),
+        };
+
+        let mut header = format!("{}={}; Path={}", self.cookie_name, value, self.path);
+        if let Some(max_age) = self.max_age_seconds {
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        header
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+// Coalesces concurrent fetches of the same cache key: the first caller leads
+// and fetches from the target, later callers wait for it to finish and then
+// look in the cache again.
+#[derive(Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<HashMap<String, watch::Receiver<()>>>,
+}
+
+pub enum Flight {
+    Leader(FlightGuard),
+    Follower(watch::Receiver<()>),
+}
+
+// Held by the leader until its response is stored. Dropping it releases the
+// key and wakes the followers.
+pub struct FlightGuard {
+    flights: Arc<SingleFlight>,
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl SingleFlight {
+    pub fn join(self: &Arc<Self>, key: &str) -> Flight {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(done) = in_flight.get(key) {
+            return Flight::Follower(done.clone());
+        }
+        let (done, receiver) = watch::channel(());
+        in_flight.insert(key.to_string(), receiver);
+        Flight::Leader(FlightGuard {
+            flights: Arc::clone(self),
+            key: key.to_string(),
+            _done: done,
+        })
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.flights.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+// Waits until the leader of a flight has finished.
+pub async fn wait(mut done: watch::Receiver<()>) {
+    while done.changed().await.is_ok() {}
+}
@@ -1,5 +1,7 @@
+use crate::config::RetryStrategyOverride;
 use crate::config_manager::ConfigManager;
 use crate::retry::connect_with_retry;
+use crate::retry_budget::RetryBudget;
 use async_trait::async_trait;
 use mobc::Manager;
 use std::io;
@@ -11,16 +13,22 @@ use tokio::sync::RwLock;
 pub struct TcpConnectionManager {
     pub server_addresses: Vec<String>,
     pub config_manager: Arc<RwLock<ConfigManager>>,
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    pub retry_override: Option<RetryStrategyOverride>,
 }
 
 impl TcpConnectionManager {
     pub fn initialize_with(
         server_addresses: Vec<String>,
         config_manager: Arc<RwLock<ConfigManager>>,
+        retry_budget: Option<Arc<RetryBudget>>,
+        retry_override: Option<RetryStrategyOverride>,
     ) -> Self {
         TcpConnectionManager {
             server_addresses,
             config_manager,
+            retry_budget,
+            retry_override,
         }
     }
 }
@@ -35,8 +43,17 @@ impl Manager for TcpConnectionManager {
             let config_manager = self.config_manager.read().await;
             config_manager.get_config().await.retry_strategy
         };
+        let retry_strategy_config = match &self.retry_override {
+            Some(retry_override) => retry_override.apply_to(&retry_strategy_config),
+            None => retry_strategy_config,
+        };
 
-        connect_with_retry(&self.server_addresses, retry_strategy_config).await
+        connect_with_retry(
+            &self.server_addresses,
+            retry_strategy_config,
+            self.retry_budget.as_deref(),
+        )
+        .await
     }
 
     async fn check(&self, mut conn: Self::Connection) -> Result<Self::Connection, Self::Error> {
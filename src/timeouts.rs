@@ -0,0 +1,172 @@
+use crate::config::TimeoutsConfig;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+const DEFAULT_CLIENT_HEADER_MILLIS: u64 = 30_000;
+const DEFAULT_IDLE_MILLIS: u64 = 300_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    ClientHeader,
+    ClientBody,
+    UpstreamConnect,
+    UpstreamFirstByte,
+    Idle,
+}
+
+impl TimeoutPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeoutPhase::ClientHeader => "client_header",
+            TimeoutPhase::ClientBody => "client_body",
+            TimeoutPhase::UpstreamConnect => "upstream_connect",
+            TimeoutPhase::UpstreamFirstByte => "upstream_first_byte",
+            TimeoutPhase::Idle => "idle",
+        }
+    }
+
+    // The status to answer with, or None once the response is under way.
+    pub fn status(&self) -> Option<&'static str> {
+        match self {
+            TimeoutPhase::ClientHeader | TimeoutPhase::ClientBody => Some("408 Request Timeout"),
+            TimeoutPhase::UpstreamConnect | TimeoutPhase::UpstreamFirstByte => {
+                Some("504 Gateway Timeout")
+            }
+            TimeoutPhase::Idle => None,
+        }
+    }
+}
+
+// Carried inside an `io::ErrorKind::TimedOut` error so callers can tell which
+// phase ran out of time and whether the overall deadline was the cause.
+#[derive(Debug)]
+pub struct PhaseTimeout {
+    pub phase: TimeoutPhase,
+    pub deadline_exceeded: bool,
+}
+
+impl fmt::Display for PhaseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.deadline_exceeded {
+            write!(
+                f,
+                "request deadline exceeded during {}",
+                self.phase.as_str()
+            )
+        } else {
+            write!(f, "{} timed out", self.phase.as_str())
+        }
+    }
+}
+
+impl Error for PhaseTimeout {}
+
+impl PhaseTimeout {
+    pub fn from_error(error: &io::Error) -> Option<&PhaseTimeout> {
+        error.get_ref()?.downcast_ref::<PhaseTimeout>()
+    }
+}
+
+pub struct RouteTimeouts {
+    client_header: Option<Duration>,
+    client_body: Option<Duration>,
+    upstream_connect: Option<Duration>,
+    upstream_first_byte: Option<Duration>,
+    idle: Option<Duration>,
+    total: Option<Duration>,
+}
+
+impl RouteTimeouts {
+    // `connect_timeout` is the route's `timeout_seconds`, used unless an
+    // upstream connect timeout is configured.
+    pub fn new(config: Option<&TimeoutsConfig>, connect_timeout: Duration) -> Self {
+        let millis = |value: Option<u64>| value.map(Duration::from_millis);
+        match config {
+            Some(config) => RouteTimeouts {
+                client_header: Some(Duration::from_millis(
+                    config
+                        .client_header_millis
+                        .unwrap_or(DEFAULT_CLIENT_HEADER_MILLIS),
+                )),
+                client_body: millis(config.client_body_millis),
+                upstream_connect: Some(
+                    millis(config.upstream_connect_millis).unwrap_or(connect_timeout),
+                ),
+                upstream_first_byte: millis(config.upstream_first_byte_millis),
+                idle: Some(Duration::from_millis(
+                    config.idle_millis.unwrap_or(DEFAULT_IDLE_MILLIS),
+                )),
+                total: millis(config.total_millis),
+            },
+            None => RouteTimeouts {
+                client_header: Some(Duration::from_millis(DEFAULT_CLIENT_HEADER_MILLIS)),
+                client_body: None,
+                upstream_connect: Some(connect_timeout),
+                upstream_first_byte: None,
+                idle: Some(Duration::from_millis(DEFAULT_IDLE_MILLIS)),
+                total: None,
+            },
+        }
+    }
+
+    // Starts the clock for one request.
+    pub fn start(&self) -> RequestDeadline<'_> {
+        RequestDeadline {
+            timeouts: self,
+            deadline: self.total.map(|total| Instant::now() + total),
+        }
+    }
+
+    fn limit(&self, phase: TimeoutPhase) -> Option<Duration> {
+        match phase {
+            TimeoutPhase::ClientHeader => self.client_header,
+            TimeoutPhase::ClientBody => self.client_body,
+            TimeoutPhase::UpstreamConnect => self.upstream_connect,
+            TimeoutPhase::UpstreamFirstByte => self.upstream_first_byte,
+            TimeoutPhase::Idle => self.idle,
+        }
+    }
+}
+
+// Bounds every phase of a request by both its own timeout and whatever is
+// left of the request's total duration.
+pub struct RequestDeadline<'a> {
+    timeouts: &'a RouteTimeouts,
+    deadline: Option<Instant>,
+}
+
+impl RequestDeadline<'_> {
+    // What is left of the total duration, if one is configured.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub async fn run<T, F>(&self, phase: TimeoutPhase, future: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        let phase_limit = self.timeouts.limit(phase);
+        let remaining = self.remaining();
+        let (limit, deadline_exceeded) = match (phase_limit, remaining) {
+            (Some(phase_limit), Some(remaining)) if remaining < phase_limit => (remaining, true),
+            (Some(phase_limit), _) => (phase_limit, false),
+            (None, Some(remaining)) => (remaining, true),
+            (None, None) => return future.await,
+        };
+        match time::timeout(limit, future).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                PhaseTimeout {
+                    phase,
+                    deadline_exceeded,
+                },
+            )),
+        }
+    }
+}